@@ -0,0 +1,23 @@
+-- A library written before schema migrations, user_version is still 0.
+CREATE TABLE feeds (
+   id              INTEGER PRIMARY KEY,
+   url             TEXT NOT NULL,
+   name            TEXT NOT NULL
+);
+
+CREATE TABLE episodes (
+   id              INTEGER PRIMARY KEY,
+   url             TEXT NOT NULL,
+   title           TEXT NOT NULL,
+   description     TEXT NOT NULL,
+   feed_id         INTEGER
+);
+
+INSERT INTO feeds (id, url, name) VALUES
+   (1, 'https://feeds.simplecast.com/sY509q85', 'Laracasts'),
+   (2, 'https://rss.art19.com/smartless', 'Smartless');
+
+INSERT INTO episodes (id, url, title, description, feed_id) VALUES
+   (1, 'https://example.com/1.mp3', 'First', 'the first episode', 1),
+   (2, 'https://example.com/2.mp3', 'Second', 'the second episode', 1),
+   (3, 'https://example.com/s1.mp3', 'Pilot', '', 2);
@@ -0,0 +1,512 @@
+use crate::filename;
+use crate::settings::Settings;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "Usage: minicast [OPTIONS] [COMMAND]
+
+Commands:
+  sync [DIR]             copy the queued and unplayed downloads to DIR
+                         (default: sync_dir in the config file)
+  opml import <FILE>     subscribe to the feeds of an OPML file
+  opml export [FILE]     write the subscriptions as OPML (default: stdout)
+
+Options:
+  -c, --config <FILE>    config file [env: MINICAST_CONFIG]
+                         (default: $XDG_CONFIG_HOME/minicast/config.toml)
+      --database <FILE>  library database [env: MINICAST_DATABASE]
+                         (default: $XDG_DATA_HOME/minicast/db.sqlite)
+      --theme <FILE>     cursive theme [env: MINICAST_THEME]
+                         (default: $XDG_CONFIG_HOME/minicast/style.toml)
+  -h, --help             print this help
+  -V, --version          print the version";
+
+/// The theme used when no theme file is found.
+pub const DEFAULT_THEME: &str = include_str!("../themes/style.toml");
+
+/// Where minicast keeps its files and how it behaves.
+///
+/// Every value comes from, in order of precedence, a command line flag,
+/// an environment variable, the config file or the default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub database: PathBuf,
+    /// `None` uses `DEFAULT_THEME`.
+    pub theme: Option<PathBuf>,
+    pub settings: Settings,
+}
+
+/// What minicast was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<Config>),
+    /// sync the downloads to `settings.sync_dir` and exit.
+    Sync(Box<Config>),
+    /// subscribe to the feeds of an OPML file and exit.
+    ImportOpml(Box<Config>, PathBuf),
+    /// write the feeds as OPML to a file, or stdout, and exit.
+    ExportOpml(Box<Config>, Option<PathBuf>),
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Usage(String),
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(PathBuf, String),
+    /// neither the XDG variables nor HOME are set.
+    NoHome,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            ConfigError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::NoHome => write!(f, "HOME is not set, can't find the config directory"),
+        }
+    }
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+#[derive(Default)]
+struct Flags {
+    config: Option<PathBuf>,
+    database: Option<PathBuf>,
+    theme: Option<PathBuf>,
+}
+
+/// Parse the command line (without the program name) and load the
+/// config it points to, `env` looks up environment variables.
+pub fn load<F>(args: &[String], env: F) -> Result<Command, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut flags = Flags::default();
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positional.push(arg.as_str());
+            continue;
+        }
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let slot = match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-c" | "--config" => &mut flags.config,
+            "--database" => &mut flags.database,
+            "--theme" => &mut flags.theme,
+            _ => return Err(ConfigError::Usage(format!("unknown argument {}", arg))),
+        };
+        let value = inline
+            .or_else(|| args.next().cloned())
+            .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", name)))?;
+        *slot = Some(PathBuf::from(value));
+    }
+
+    let env_path = |name: &str| env(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    let config_dir = xdg_dir(&env, "XDG_CONFIG_HOME", ".config")?.join("minicast");
+    let data_dir = xdg_dir(&env, "XDG_DATA_HOME", ".local/share")?.join("minicast");
+
+    // a missing config file is only an error if it was asked for.
+    let (config_path, required) = match flags.config.or_else(|| env_path("MINICAST_CONFIG")) {
+        Some(path) => (path, true),
+        None => (config_dir.join("config.toml"), false),
+    };
+    let file = match fs::read_to_string(&config_path) {
+        Ok(content) => parse_file(&config_path, &content, &env)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => ConfigFile::default(),
+        Err(e) => return Err(ConfigError::Io(config_path, e)),
+    };
+
+    let database = flags
+        .database
+        .or_else(|| env_path("MINICAST_DATABASE"))
+        .or(file.database)
+        .unwrap_or_else(|| data_dir.join("db.sqlite"));
+
+    let mut settings = file.settings;
+    settings.download_dir = file
+        .download_dir
+        .unwrap_or_else(|| data_dir.join("episodes"));
+
+    let default_theme = config_dir.join("style.toml");
+    let theme = flags
+        .theme
+        .or_else(|| env_path("MINICAST_THEME"))
+        .or(file.theme)
+        .or_else(|| Some(default_theme).filter(|path| path.exists()));
+
+    let config = Box::new(Config {
+        database,
+        theme,
+        settings,
+    });
+    match positional[..] {
+        [] => Ok(Command::Run(config)),
+        ["sync", ref rest @ ..] => sync_command(config, rest),
+        ["opml", "import", file] => Ok(Command::ImportOpml(config, PathBuf::from(file))),
+        ["opml", "export"] => Ok(Command::ExportOpml(config, None)),
+        ["opml", "export", file] => Ok(Command::ExportOpml(config, Some(PathBuf::from(file)))),
+        ["opml", ..] => Err(ConfigError::Usage(
+            "opml needs import <FILE> or export [FILE]".into(),
+        )),
+        [command, ..] => Err(ConfigError::Usage(format!("unknown command {}", command))),
+    }
+}
+
+fn sync_command(mut config: Box<Config>, args: &[&str]) -> Result<Command, ConfigError> {
+    match args {
+        [] => {}
+        [dir] => config.settings.sync_dir = Some(PathBuf::from(dir)),
+        _ => return Err(ConfigError::Usage("sync takes one directory".into())),
+    }
+    if config.settings.sync_dir.is_none() {
+        return Err(ConfigError::Usage(
+            "sync needs a directory, or sync_dir in the config file".into(),
+        ));
+    }
+    Ok(Command::Sync(config))
+}
+
+/// `$<var>`, or `$HOME/<fallback>` as the XDG base directory spec says.
+fn xdg_dir<F>(env: &F, var: &str, fallback: &str) -> Result<PathBuf, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match env(var).filter(|dir| Path::new(dir).is_absolute()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => env("HOME")
+            .filter(|home| !home.is_empty())
+            .map(|home| Path::new(&home).join(fallback))
+            .ok_or(ConfigError::NoHome),
+    }
+}
+
+#[derive(Default)]
+struct ConfigFile {
+    database: Option<PathBuf>,
+    theme: Option<PathBuf>,
+    download_dir: Option<PathBuf>,
+    settings: Settings,
+}
+
+fn parse_file<F>(path: &Path, content: &str, env: &F) -> Result<ConfigFile, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(ConfigError::Parse(path.into(), "not a table".into())),
+        Err(e) => return Err(ConfigError::Parse(path.into(), e.to_string())),
+    };
+    let invalid = |key: &str, expected: &str| {
+        ConfigError::Invalid(path.into(), format!("{} should be {}", key, expected))
+    };
+
+    // paths in the file are relative to the file itself.
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let resolve = |key: &str, value: &toml::Value| match value.as_str() {
+        Some(value) => Ok(resolve_path(base, value, env)),
+        None => Err(invalid(key, "a path")),
+    };
+
+    let mut file = ConfigFile::default();
+    let settings = &mut file.settings;
+    for (key, value) in &table {
+        match key.as_str() {
+            "database" => file.database = Some(resolve(key, value)?),
+            "theme" => file.theme = Some(resolve(key, value)?),
+            "download_dir" => file.download_dir = Some(resolve(key, value)?),
+            "filename_template" | "sync_template" => {
+                let template = value.as_str().ok_or_else(|| invalid(key, "a string"))?;
+                filename::check(template)
+                    .map_err(|e| ConfigError::Invalid(path.into(), format!("{}: {}", key, e)))?;
+                match key.as_str() {
+                    "filename_template" => settings.filename_template = template.to_string(),
+                    _ => settings.sync_template = template.to_string(),
+                }
+            }
+            "sync_dir" => settings.sync_dir = Some(resolve(key, value)?),
+            "parallel_downloads" => {
+                settings.parallel_downloads = integer(value, key, &invalid)?;
+            }
+            // in megabytes, 0 is no quota.
+            "download_quota" => {
+                let megabytes: u64 = integer(value, key, &invalid)?;
+                let bytes = megabytes
+                    .checked_mul(1_000_000)
+                    .ok_or_else(|| invalid(key, "a number of megabytes"))?;
+                settings.download_quota = Some(bytes).filter(|&bytes| bytes > 0);
+            }
+            "played_threshold" => {
+                settings.played_threshold = value
+                    .as_float()
+                    .filter(|v| (0.0..=1.0).contains(v))
+                    .ok_or_else(|| invalid(key, "a number between 0 and 1"))?
+            }
+            "refresh_interval" => settings.refresh_interval = integer(value, key, &invalid)?,
+            "network" => {
+                let network = value.as_table().ok_or_else(|| invalid(key, "a table"))?;
+                let settings = &mut settings.network;
+                for (name, value) in network {
+                    let key = format!("network.{}", name);
+                    match name.as_str() {
+                        "proxy" => {
+                            let proxy = value.as_str().ok_or_else(|| invalid(&key, "a string"))?;
+                            settings.proxy = Some(proxy.to_string()).filter(|p| !p.is_empty());
+                        }
+                        "connect_timeout" => {
+                            settings.connect_timeout = integer(value, &key, &invalid)
+                                .ok()
+                                .filter(|&seconds| seconds > 0)
+                                .ok_or_else(|| invalid(&key, "a number of seconds above 0"))?
+                        }
+                        "read_timeout" => settings.read_timeout = integer(value, &key, &invalid)?,
+                        "retries" => settings.retries = integer(value, &key, &invalid)?,
+                        "retry_delay" => settings.retry_delay = integer(value, &key, &invalid)?,
+                        "user_agent" => {
+                            settings.user_agent = value
+                                .as_str()
+                                .ok_or_else(|| invalid(&key, "a string"))?
+                                .to_string()
+                        }
+                        _ => {
+                            return Err(ConfigError::Invalid(
+                                path.into(),
+                                format!("unknown key {}", key),
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(ConfigError::Invalid(
+                    path.into(),
+                    format!("unknown key {}", key),
+                ))
+            }
+        }
+    }
+
+    Ok(file)
+}
+
+fn integer<T, F>(value: &toml::Value, key: &str, invalid: &F) -> Result<T, ConfigError>
+where
+    T: std::convert::TryFrom<i64>,
+    F: Fn(&str, &str) -> ConfigError,
+{
+    value
+        .as_integer()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| invalid(key, "a positive number"))
+}
+
+/// Expand a leading `~/` of a path typed in the interface.
+pub fn expand_home(value: &str) -> PathBuf {
+    resolve_path(Path::new(""), value, &|name: &str| std::env::var(name).ok())
+}
+
+/// Expand a leading `~/` and make `value` relative to `base`.
+fn resolve_path<F>(base: &Path, value: &str, env: &F) -> PathBuf
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match (value.strip_prefix("~/"), env("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => base.join(value),
+    }
+}
+
+#[cfg(test)]
+fn test_env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+    move |name| {
+        vars.iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.to_string())
+    }
+}
+
+#[cfg(test)]
+fn run_config(command: Command) -> Config {
+    match command {
+        Command::Run(config) => *config,
+        command => panic!("expected a config, got {:?}", command),
+    }
+}
+
+#[test]
+fn test_config_defaults() {
+    let config = run_config(load(&[], test_env(&[("HOME", "/nonexistent")])).unwrap());
+    assert_eq!(
+        config.database,
+        PathBuf::from("/nonexistent/.local/share/minicast/db.sqlite")
+    );
+    assert_eq!(config.theme, None);
+    assert_eq!(
+        config.settings.download_dir,
+        PathBuf::from("/nonexistent/.local/share/minicast/episodes")
+    );
+    assert_eq!(config.settings.network, Settings::default().network);
+
+    let env = test_env(&[
+        ("HOME", "/nonexistent"),
+        ("XDG_DATA_HOME", "/data"),
+        ("XDG_CONFIG_HOME", "relative/is/ignored"),
+    ]);
+    let config = run_config(load(&[], env).unwrap());
+    assert_eq!(config.database, PathBuf::from("/data/minicast/db.sqlite"));
+
+    assert!(matches!(load(&[], test_env(&[])), Err(ConfigError::NoHome)));
+}
+
+#[test]
+fn test_config_overrides() {
+    let env = test_env(&[
+        ("HOME", "/nonexistent"),
+        ("MINICAST_DATABASE", "/env/db.sqlite"),
+        ("MINICAST_THEME", "/env/style.toml"),
+    ]);
+    let config = run_config(load(&[], &env).unwrap());
+    assert_eq!(config.database, PathBuf::from("/env/db.sqlite"));
+    assert_eq!(config.theme, Some(PathBuf::from("/env/style.toml")));
+
+    let args: Vec<String> = vec!["--database".into(), "/cli/db.sqlite".into()];
+    let config = run_config(load(&args, &env).unwrap());
+    assert_eq!(config.database, PathBuf::from("/cli/db.sqlite"));
+
+    let args: Vec<String> = vec!["--theme=/cli/style.toml".into()];
+    let config = run_config(load(&args, &env).unwrap());
+    assert_eq!(config.theme, Some(PathBuf::from("/cli/style.toml")));
+
+    assert_eq!(load(&["-h".into()], &env).unwrap(), Command::Help);
+    let args: Vec<String> = vec!["sync".into(), "/media/player".into()];
+    match load(&args, &env).unwrap() {
+        Command::Sync(config) => {
+            assert_eq!(
+                config.settings.sync_dir,
+                Some(PathBuf::from("/media/player"))
+            )
+        }
+        command => panic!("expected sync, got {:?}", command),
+    }
+    // without a directory in the config file.
+    assert!(matches!(
+        load(&["sync".into()], &env),
+        Err(ConfigError::Usage(_))
+    ));
+    assert!(matches!(
+        load(&["play".into()], &env),
+        Err(ConfigError::Usage(_))
+    ));
+
+    let args: Vec<String> = vec!["opml".into(), "import".into(), "feeds.opml".into()];
+    assert!(matches!(
+        load(&args, &env).unwrap(),
+        Command::ImportOpml(_, path) if path == Path::new("feeds.opml")
+    ));
+    let args: Vec<String> = vec!["opml".into(), "export".into()];
+    assert!(matches!(
+        load(&args, &env).unwrap(),
+        Command::ExportOpml(_, None)
+    ));
+    assert!(matches!(
+        load(&["opml".into(), "import".into()], &env),
+        Err(ConfigError::Usage(_))
+    ));
+    assert!(matches!(
+        load(&["--nope".into()], &env),
+        Err(ConfigError::Usage(_))
+    ));
+    assert!(matches!(
+        load(&["--database".into()], &env),
+        Err(ConfigError::Usage(_))
+    ));
+    // an explicit config file has to exist.
+    assert!(matches!(
+        load(&["-c".into(), "/nonexistent/config.toml".into()], &env),
+        Err(ConfigError::Io(..))
+    ));
+}
+
+#[test]
+fn test_config_file() {
+    let env = test_env(&[("HOME", "/home/me")]);
+    let path = Path::new("/etc/minicast/config.toml");
+    let file = parse_file(
+        path,
+        r#"
+            database = "library.sqlite"
+            download_dir = "/media/podcasts"
+            parallel_downloads = 4
+            filename_template = "{feed}/{id}.{ext}"
+            sync_dir = "/media/player"
+            download_quota = 2000
+            theme = "~/themes/dark.toml"
+            played_threshold = 0.9
+            refresh_interval = 0
+
+            [network]
+            proxy = "socks5://localhost:1080"
+            connect_timeout = 5
+            retries = 5
+            user_agent = "podcatcher"
+        "#,
+        &env,
+    )
+    .unwrap();
+    assert_eq!(
+        file.database,
+        Some(PathBuf::from("/etc/minicast/library.sqlite"))
+    );
+    assert_eq!(file.theme, Some(PathBuf::from("/home/me/themes/dark.toml")));
+    assert_eq!(file.download_dir, Some(PathBuf::from("/media/podcasts")));
+    assert_eq!(file.settings.parallel_downloads, 4);
+    assert_eq!(file.settings.filename_template, "{feed}/{id}.{ext}");
+    assert_eq!(file.settings.sync_dir, Some(PathBuf::from("/media/player")));
+    assert_eq!(file.settings.download_quota, Some(2_000_000_000));
+    assert_eq!(file.settings.played_threshold, 0.9);
+    assert_eq!(file.settings.refresh_interval, 0);
+    assert_eq!(
+        file.settings.network.proxy,
+        Some("socks5://localhost:1080".into())
+    );
+    assert_eq!(file.settings.network.connect_timeout, 5);
+    assert_eq!(file.settings.network.read_timeout, 30);
+    assert_eq!(file.settings.network.retries, 5);
+    assert_eq!(file.settings.network.user_agent, "podcatcher");
+
+    assert!(matches!(
+        parse_file(path, "refresh_interval = -1", &env),
+        Err(ConfigError::Invalid(..))
+    ));
+    assert!(matches!(
+        parse_file(path, "download_quota = 9223372036854775807", &env),
+        Err(ConfigError::Invalid(..))
+    ));
+    assert!(matches!(
+        parse_file(path, "[network]\ntimeot = 3", &env),
+        Err(ConfigError::Invalid(..))
+    ));
+    assert!(matches!(
+        parse_file(path, "[network]\nconnect_timeout = 0", &env),
+        Err(ConfigError::Invalid(..))
+    ));
+    assert!(matches!(
+        parse_file(path, "database = ", &env),
+        Err(ConfigError::Parse(..))
+    ));
+}
@@ -1,13 +1,50 @@
 use crate::app;
+use crate::config::Config;
+use crate::date;
+use crate::db::DownloadState;
+use crate::download::Downloader;
+use crate::feed::{Credentials, Fetched};
+use crate::fetcher::Fetcher;
+use crate::opml::Import;
 use crate::player::MediaWorker;
+use crate::retention::FeedRules;
+use crate::sync::SyncReport;
 use crate::ui::{Ui, UiMessage};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the position of the playing episode is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often feeds are checked for a scheduled refresh.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a feed whose scheduled refresh failed is left alone.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(15 * 60);
 
 pub struct Controller {
     rx: mpsc::Receiver<ControllerMessage>,
+    tx: mpsc::Sender<ControllerMessage>,
     ui: Ui,
     app: app::App,
     media: MediaWorker,
+    fetcher: Fetcher,
+    downloader: Downloader,
+    /// episodes queued on the downloader.
+    downloading: HashSet<u32>,
+    /// feeds being fetched right now.
+    refreshing: HashSet<u32>,
+    refresh_all: Option<RefreshAll>,
+    /// feeds fetched by the schedule, their failures are not shown.
+    scheduled: HashSet<u32>,
+    retry_at: HashMap<u32, Instant>,
+    last_schedule_check: Option<Instant>,
+    playing: Option<u32>,
+    selected_feed: Option<u32>,
+    last_saved: Instant,
+    /// an OPML import waiting to be confirmed.
+    pending_import: Option<Import>,
 }
 
 pub enum ControllerMessage {
@@ -16,21 +53,84 @@ pub enum ControllerMessage {
     UpdateStopPlayer,
 
     DeleteFeed(u32),
-    AddNewFeed(String, String),
+    AddNewFeed(String, String, Option<Credentials>),
     UpdatePlayEpisode(u32),
     UpdateSelectedFeed(u32),
     ReloadFeedEpisodes(u32),
+    RefreshAllFeeds,
+    /// open the refresh interval dialog of a feed.
+    EditRefreshInterval(u32),
+    SetRefreshInterval(u32, Option<u32>),
+    /// open the download rules dialog of a feed.
+    EditFeedRules(u32),
+    SetFeedRules(u32, FeedRules),
+    /// show what the retention rules would do, without doing it.
+    RetentionReport,
+    /// copy the downloads to the sync directory.
+    Sync,
+    SyncFinished(Result<SyncReport, String>),
+    /// read an OPML file and preview what it adds.
+    ImportOpml(PathBuf),
+    /// add the feeds of the previewed OPML file.
+    ConfirmImport,
+    ExportOpml(PathBuf),
+    MarkPlayed(u32, bool),
+
+    Enqueue(u32),
+    Dequeue(u32),
+    MoveQueued(u32, isize),
+    ClearQueue,
+    PlayNext,
+
+    FetchStarted(u32),
+    /// a fetched feed and the url it permanently moved to.
+    FetchFinished(u32, Fetched, Option<String>),
+    FetchFailed(u32, String),
+
+    Download(u32),
+    DownloadStarted(u32),
+    /// bytes written and the size of the file, if known.
+    DownloadProgress(u32, u64, Option<u64>),
+    DownloadFinished(u32),
+    DownloadFailed(u32, String),
+    TaggingFailed(u32, String),
 }
 
 impl Controller {
-    pub fn new() -> Result<Controller, String> {
+    pub fn new(config: &Config) -> Result<Controller, String> {
         let (tx, rx) = mpsc::channel::<ControllerMessage>();
-        Ok(Controller {
-            rx: rx,
-            app: app::App::new(),
-            media: MediaWorker::new().expect("can't open media"),
-            ui: Ui::new(tx.clone()),
-        })
+        let app = app::App::new(config)?;
+        let mut media = MediaWorker::new().expect("can't open media");
+        media
+            .set_network(app.network_settings())
+            .map_err(|e| e.to_string())?;
+        let downloader = Downloader::new(tx.clone(), config.settings.parallel_downloads);
+        let mut controller = Controller {
+            rx,
+            tx: tx.clone(),
+            app,
+            media,
+            ui: Ui::new(tx.clone(), config.theme.as_deref())?,
+            fetcher: Fetcher::new(tx.clone()),
+            downloader,
+            downloading: HashSet::new(),
+            refreshing: HashSet::new(),
+            refresh_all: None,
+            scheduled: HashSet::new(),
+            retry_at: HashMap::new(),
+            last_schedule_check: None,
+            playing: None,
+            selected_feed: None,
+            last_saved: Instant::now(),
+            pending_import: None,
+        };
+
+        // partial files are picked up where they were left.
+        for (episode, request) in controller.app.get_unfinished_downloads() {
+            controller.downloading.insert(episode);
+            controller.downloader.download(episode, request);
+        }
+        Ok(controller)
     }
 
     pub fn run(&mut self) {
@@ -43,49 +143,509 @@ impl Controller {
                         self.media.time_position().unwrap_or("".into()),
                     ))
                     .unwrap();
+
+                if self.last_saved.elapsed() >= SAVE_INTERVAL {
+                    self.save_playback();
+                }
+            }
+
+            let check_due = match self.last_schedule_check {
+                Some(last) => last.elapsed() >= SCHEDULE_INTERVAL,
+                None => true,
+            };
+            if check_due {
+                self.refresh_due_feeds();
+                self.apply_retention();
+            }
+
+            if self.playing.is_some() && self.media.eof_reached() {
+                self.save_playback();
+                self.play_next();
             }
+
             while let Some(message) = self.rx.try_iter().next() {
                 match message {
                     ControllerMessage::LoadFeeds => {
-                        let feeds = self.app.get_feeds_name_id();
-                        self.ui.ui_tx.send(UiMessage::UpdateFeeds(feeds)).unwrap();
+                        self.send_feeds();
+                        self.send_queue();
                     }
                     ControllerMessage::UpdatePlayEpisode(episode) => {
-                        let episode = self.app.get_episode(episode);
+                        self.play_episode(episode);
+                    }
+                    ControllerMessage::Enqueue(episode) => {
+                        self.app.enqueue(episode);
+                        self.send_queue();
+                    }
+                    ControllerMessage::Dequeue(episode) => {
+                        self.app.dequeue(episode);
+                        self.send_queue();
+                    }
+                    ControllerMessage::MoveQueued(episode, offset) => {
+                        self.app.move_queued(episode, offset);
+                        self.send_queue();
+                    }
+                    ControllerMessage::ClearQueue => {
+                        self.app.clear_queue();
+                        self.send_queue();
+                    }
+                    ControllerMessage::PlayNext => {
+                        self.save_playback();
+                        self.play_next();
+                    }
+                    ControllerMessage::ReloadFeedEpisodes(feed) => {
+                        self.scheduled.remove(&feed);
+                        self.fetch(feed);
+                    }
+                    ControllerMessage::RefreshAllFeeds => {
+                        // the fetcher pool bounds how many run at once.
+                        let mut refresh_all = RefreshAll::default();
+                        for feed in self.app.get_feed_ids() {
+                            self.scheduled.remove(&feed);
+                            if self.fetch(feed) {
+                                refresh_all.pending.insert(feed);
+                            }
+                        }
+                        self.refresh_all = Some(refresh_all);
+                        self.send_refresh_summary();
+                    }
+                    ControllerMessage::EditRefreshInterval(feed) => {
+                        let interval = self.app.get_refresh_interval(feed);
                         self.ui
                             .ui_tx
-                            .send(UiMessage::UpdatePlaying(episode.title, episode.description))
+                            .send(UiMessage::ShowRefreshInterval(feed, interval))
                             .unwrap();
-
-                        self.media.loadfile(&episode.url).unwrap();
                     }
-                    ControllerMessage::ReloadFeedEpisodes(feed) => {
-                        self.app.reload_episodes(feed);
-                        let eps = self.app.get_episodes_title_id(feed);
-                        self.ui.ui_tx.send(UiMessage::UpdateEpisodes(eps)).unwrap();
+                    ControllerMessage::SetRefreshInterval(feed, interval) => {
+                        self.app.set_refresh_interval(feed, interval);
+                        self.send_feed_details();
+                    }
+                    ControllerMessage::EditFeedRules(feed) => {
+                        let rules = self.app.get_feed_rules(feed);
+                        self.ui
+                            .ui_tx
+                            .send(UiMessage::ShowFeedRules(feed, rules))
+                            .unwrap();
+                    }
+                    ControllerMessage::SetFeedRules(feed, rules) => {
+                        self.app.set_feed_rules(feed, &rules);
+                        self.send_feed_details();
+                        self.apply_retention();
+                    }
+                    ControllerMessage::RetentionReport => {
+                        let plan = self.app.plan_retention(&self.protected_episodes());
+                        let report = self.app.retention_report(&plan);
+                        self.ui
+                            .ui_tx
+                            .send(UiMessage::ShowInfo("Retention".into(), report))
+                            .unwrap();
+                    }
+                    ControllerMessage::Sync => match self.app.sync_job() {
+                        Ok(job) => {
+                            let tx = self.tx.clone();
+                            thread::spawn(move || {
+                                let _ = tx.send(ControllerMessage::SyncFinished(job.run()));
+                            });
+                        }
+                        Err(e) => self.ui.ui_tx.send(UiMessage::ShowError(e)).unwrap(),
+                    },
+                    ControllerMessage::SyncFinished(result) => {
+                        let message = match result {
+                            Ok(report) => UiMessage::ShowInfo("Sync".into(), report.to_string()),
+                            Err(e) => UiMessage::ShowError(e),
+                        };
+                        self.ui.ui_tx.send(message).unwrap();
+                    }
+                    ControllerMessage::ImportOpml(path) => match self.app.read_opml(&path) {
+                        Ok(import) if import.new.is_empty() => self
+                            .ui
+                            .ui_tx
+                            .send(UiMessage::ShowInfo("Import".into(), import.summary()))
+                            .unwrap(),
+                        Ok(import) => {
+                            self.ui
+                                .ui_tx
+                                .send(UiMessage::ShowImport(import.summary()))
+                                .unwrap();
+                            self.pending_import = Some(import);
+                        }
+                        Err(e) => self.ui.ui_tx.send(UiMessage::ShowError(e)).unwrap(),
+                    },
+                    ControllerMessage::ConfirmImport => {
+                        if let Some(import) = self.pending_import.take() {
+                            for feed in self.app.import_feeds(&import) {
+                                self.fetch(feed);
+                            }
+                            self.send_feeds();
+                        }
+                    }
+                    ControllerMessage::ExportOpml(path) => {
+                        let message = match self.app.export_opml(&path) {
+                            Ok(count) => UiMessage::ShowInfo(
+                                "Export".into(),
+                                format!("Exported {} feeds to {}", count, path.display()),
+                            ),
+                            Err(e) => UiMessage::ShowError(e),
+                        };
+                        self.ui.ui_tx.send(message).unwrap();
+                    }
+                    ControllerMessage::FetchStarted(feed) => {
+                        self.refreshing.insert(feed);
+                        self.send_feeds();
+                    }
+                    ControllerMessage::FetchFinished(feed, fetched, moved_to) => {
+                        self.refreshing.remove(&feed);
+                        self.scheduled.remove(&feed);
+                        self.retry_at.remove(&feed);
+                        let new = self.app.store_fetched(feed, fetched, moved_to);
+                        self.apply_retention();
+                        self.refresh_lists();
+                        if let Some(refresh_all) = self.refresh_all.as_mut() {
+                            refresh_all.finished(feed, self.app.get_feed_label(feed), new);
+                        }
+                        self.send_refresh_summary();
+                    }
+                    ControllerMessage::FetchFailed(feed, e) => {
+                        self.refreshing.remove(&feed);
+                        self.send_feeds();
+                        if self.scheduled.remove(&feed) {
+                            self.retry_at
+                                .insert(feed, Instant::now() + RETRY_AFTER_FAILURE);
+                            continue;
+                        }
+                        if let Some(refresh_all) = self.refresh_all.as_mut() {
+                            if refresh_all.pending.contains(&feed) {
+                                refresh_all.failed(feed, self.app.get_feed_label(feed), e);
+                                self.send_refresh_summary();
+                                continue;
+                            }
+                        }
+                        self.ui
+                            .ui_tx
+                            .send(UiMessage::ShowError(format!(
+                                "Couldn't fetch {}: {}",
+                                self.app.get_feed_label(feed),
+                                e
+                            )))
+                            .unwrap();
+                    }
+                    ControllerMessage::Download(episode) => {
+                        if self.downloading.contains(&episode) {
+                            continue;
+                        }
+                        if let Some(request) = self.app.download_request(episode) {
+                            self.downloading.insert(episode);
+                            self.downloader.download(episode, request);
+                            self.refresh_episodes_of(episode);
+                        }
+                    }
+                    ControllerMessage::DownloadStarted(episode) => {
+                        self.app
+                            .set_download_state(episode, DownloadState::Downloading);
+                    }
+                    ControllerMessage::DownloadProgress(episode, bytes, total) => {
+                        self.app.set_download_progress(episode, bytes, total);
+                        self.refresh_episodes_of(episode);
+                    }
+                    ControllerMessage::DownloadFinished(episode) => {
+                        self.downloading.remove(&episode);
+                        self.app.set_download_state(episode, DownloadState::Done);
+                        self.refresh_episodes_of(episode);
+                    }
+                    ControllerMessage::TaggingFailed(episode, e) => {
+                        self.ui
+                            .ui_tx
+                            .send(UiMessage::ShowError(format!(
+                                "Couldn't tag {}: {}",
+                                self.app.get_episode(episode).title,
+                                e
+                            )))
+                            .unwrap();
+                    }
+                    ControllerMessage::DownloadFailed(episode, e) => {
+                        self.downloading.remove(&episode);
+                        self.app.set_download_failed(episode, &e);
+                        self.refresh_episodes_of(episode);
+                        self.ui
+                            .ui_tx
+                            .send(UiMessage::ShowError(format!(
+                                "Couldn't download {}: {}",
+                                self.app.get_episode(episode).title,
+                                e
+                            )))
+                            .unwrap();
                     }
                     ControllerMessage::UpdatePlayState => {
+                        self.save_playback();
                         self.media.toggle_play().expect("can't toggle play state");
                     }
                     ControllerMessage::UpdateStopPlayer => {
+                        self.save_playback();
                         self.media.stop().expect("can't toggle play state");
+                        self.playing = None;
                     }
                     ControllerMessage::UpdateSelectedFeed(feed) => {
+                        self.selected_feed = Some(feed);
+                        self.send_feed_details();
                         let eps = self.app.get_episodes_title_id(feed);
                         self.ui.ui_tx.send(UiMessage::UpdateEpisodes(eps)).unwrap();
                     }
-                    ControllerMessage::AddNewFeed(name, url) => {
-                        self.app.add_feed(name, url);
-                        let feeds = self.app.get_feeds_name_id();
-                        self.ui.ui_tx.send(UiMessage::UpdateFeeds(feeds)).unwrap();
+                    ControllerMessage::AddNewFeed(name, url, credentials) => {
+                        let feed = self.app.add_feed(name, url, credentials);
+                        self.fetch(feed);
+                    }
+                    ControllerMessage::MarkPlayed(episode, played) => {
+                        self.app.set_played(episode, played);
+                        self.refresh_lists();
                     }
                     ControllerMessage::DeleteFeed(id) => {
                         self.app.delete_feed(id);
-                        let feeds = self.app.get_feeds_name_id();
-                        self.ui.ui_tx.send(UiMessage::UpdateFeeds(feeds)).unwrap();
+                        self.send_feeds();
+                        self.send_queue();
                     }
                 };
             }
         }
+
+        self.save_playback();
+    }
+
+    /// Load an episode, resuming it where it was left. Playing a queued
+    /// episode takes it off the queue.
+    fn play_episode(&mut self, episode: u32) {
+        let extras = self.app.get_episode_extras(episode);
+        let episode = self.app.get_episode(episode);
+        self.ui
+            .ui_tx
+            .send(UiMessage::UpdatePlaying(episode.title, episode.description))
+            .unwrap();
+        self.ui
+            .ui_tx
+            .send(UiMessage::UpdatePlayingExtras(extras))
+            .unwrap();
+
+        self.save_playback();
+        let start = self.app.get_resume_position(episode.id);
+        let authorization = match self.app.get_local_file(episode.id) {
+            Some(_) => None,
+            None => self.app.get_episode_authorization(episode.id),
+        };
+        self.media
+            .set_authorization(authorization.as_deref())
+            .unwrap();
+        let url = self.app.get_playable_url(episode.id);
+        self.media.loadfile_at(&url, start).unwrap();
+        self.playing = Some(episode.id);
+
+        self.app.dequeue(episode.id);
+        self.send_queue();
+    }
+
+    /// Play the first queued episode, or stop when the queue is empty.
+    fn play_next(&mut self) {
+        match self.app.pop_queue() {
+            Some(episode) => self.play_episode(episode),
+            None => {
+                self.media.stop().expect("can't stop player");
+                self.playing = None;
+            }
+        }
+    }
+
+    /// Queue a feed on the fetcher, unless it is being fetched already.
+    /// Returns false for unknown feeds.
+    fn fetch(&mut self, feed: u32) -> bool {
+        let request = match self.app.get_feed_request(feed) {
+            Some(request) => request,
+            None => return false,
+        };
+        if self.refreshing.insert(feed) {
+            self.fetcher.fetch(feed, request);
+            self.send_feeds();
+        }
+        true
+    }
+
+    /// Fetch the feeds whose refresh interval passed, feeds that just
+    /// failed are skipped for a while.
+    fn refresh_due_feeds(&mut self) {
+        self.last_schedule_check = Some(Instant::now());
+        let now = Instant::now();
+        self.retry_at.retain(|_, at| *at > now);
+
+        for feed in self.app.get_feeds_due(date::now()) {
+            if self.refreshing.contains(&feed) || self.retry_at.contains_key(&feed) {
+                continue;
+            }
+            self.scheduled.insert(feed);
+            self.fetch(feed);
+        }
+    }
+
+    /// Show the summary once every feed of a refresh all came back.
+    fn send_refresh_summary(&mut self) {
+        let done = match &self.refresh_all {
+            Some(refresh_all) => refresh_all.pending.is_empty(),
+            None => false,
+        };
+        if done {
+            let summary = self.refresh_all.take().unwrap().summary();
+            self.ui
+                .ui_tx
+                .send(UiMessage::ShowInfo("Refresh".into(), summary))
+                .unwrap();
+        }
     }
+
+    fn send_feed_details(&self) {
+        if let Some(feed) = self.selected_feed {
+            let details = self.app.get_feed_details(feed);
+            self.ui
+                .ui_tx
+                .send(UiMessage::UpdateFeedDetails(details))
+                .unwrap();
+        }
+    }
+
+    fn send_feeds(&self) {
+        let feeds = self
+            .app
+            .get_feeds_name_id()
+            .into_iter()
+            .map(|(label, id)| {
+                if self.refreshing.contains(&id) {
+                    (format!("{} refreshing…", label), id)
+                } else {
+                    (label, id)
+                }
+            })
+            .collect();
+        self.ui.ui_tx.send(UiMessage::UpdateFeeds(feeds)).unwrap();
+    }
+
+    fn send_queue(&self) {
+        let queue = self.app.get_queue_title_id();
+        self.ui.ui_tx.send(UiMessage::UpdateQueue(queue)).unwrap();
+    }
+
+    /// Remember where we are in the playing episode, nothing is saved
+    /// while mpv has no position (e.g. the file is still loading).
+    fn save_playback(&mut self) {
+        self.last_saved = Instant::now();
+        if let Some(episode) = self.playing {
+            if let Ok((position, duration)) = self.media.playback() {
+                if self.app.save_playback(episode, position, duration) {
+                    self.refresh_lists();
+                }
+            }
+        }
+    }
+
+    /// Run the download and retention rules of every feed.
+    fn apply_retention(&mut self) {
+        let plan = self.app.plan_retention(&self.protected_episodes());
+        if plan.is_empty() {
+            return;
+        }
+        for (episode, request) in self.app.apply_retention(&plan) {
+            if self.downloading.insert(episode) {
+                self.downloader.download(episode, request);
+            }
+        }
+        self.refresh_lists();
+    }
+
+    /// Episodes whose downloads are never deleted: the playing and the
+    /// queued ones.
+    fn protected_episodes(&self) -> HashSet<u32> {
+        let mut protected: HashSet<u32> = self.app.get_queue_ids().into_iter().collect();
+        protected.extend(self.playing);
+        protected
+    }
+
+    /// Resend the episodes of the selected feed if `episode` is one of
+    /// them.
+    fn refresh_episodes_of(&self, episode: u32) {
+        if let Some(feed) = self.selected_feed {
+            if self.app.get_episode(episode).feed_id == feed {
+                let eps = self.app.get_episodes_title_id(feed);
+                self.ui.ui_tx.send(UiMessage::UpdateEpisodes(eps)).unwrap();
+            }
+        }
+    }
+
+    /// Resend the feeds and the episodes of the selected feed, after their
+    /// played state changed.
+    fn refresh_lists(&self) {
+        self.send_feeds();
+        self.send_feed_details();
+        if let Some(feed) = self.selected_feed {
+            let eps = self.app.get_episodes_title_id(feed);
+            self.ui.ui_tx.send(UiMessage::UpdateEpisodes(eps)).unwrap();
+        }
+    }
+}
+
+/// Progress of a refresh of every feed.
+#[derive(Default)]
+struct RefreshAll {
+    pending: HashSet<u32>,
+    /// label and new episode count of the feeds fetched.
+    fetched: Vec<(String, u32)>,
+    /// label and error of the feeds that failed.
+    failed: Vec<(String, String)>,
+}
+
+impl RefreshAll {
+    fn finished(&mut self, feed: u32, label: String, new: u32) {
+        if self.pending.remove(&feed) {
+            self.fetched.push((label, new));
+        }
+    }
+
+    fn failed(&mut self, feed: u32, label: String, error: String) {
+        if self.pending.remove(&feed) {
+            self.failed.push((label, error));
+        }
+    }
+
+    fn summary(&self) -> String {
+        let mut lines = vec![];
+        for (label, new) in &self.fetched {
+            match new {
+                0 => lines.push(format!("{}: no new episodes", label)),
+                1 => lines.push(format!("{}: 1 new episode", label)),
+                _ => lines.push(format!("{}: {} new episodes", label, new)),
+            }
+        }
+        if !self.failed.is_empty() {
+            lines.push(String::new());
+            lines.push("Failed:".into());
+            for (label, error) in &self.failed {
+                lines.push(format!("{}: {}", label, error));
+            }
+        }
+        if lines.is_empty() {
+            lines.push("No feeds to refresh.".into());
+        }
+        lines.join("\n")
+    }
+}
+
+#[test]
+fn test_refresh_all_summary() {
+    let mut refresh_all = RefreshAll::default();
+    refresh_all.pending.extend([1, 2, 3]);
+
+    refresh_all.finished(2, "second".into(), 1);
+    refresh_all.failed(3, "third".into(), "HTTP status 404".into());
+    // not part of this refresh.
+    refresh_all.finished(4, "fourth".into(), 5);
+    assert!(!refresh_all.pending.is_empty());
+
+    refresh_all.finished(1, "first".into(), 0);
+    assert!(refresh_all.pending.is_empty());
+    assert_eq!(
+        refresh_all.summary(),
+        "second: 1 new episode\nfirst: no new episodes\n\nFailed:\nthird: HTTP status 404"
+    );
 }
@@ -0,0 +1,310 @@
+//! Lenient parsing of the dates found in feeds. Everything is converted to
+//! a unix timestamp in seconds.
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Parse either an rss (RFC 822/2822) or an atom (RFC 3339) date.
+pub fn parse_date(text: &str) -> Option<i64> {
+    parse_rfc2822(text).or_else(|| parse_rfc3339(text))
+}
+
+/// Parse an RFC 822/2822 date such as `Tue, 10 Jun 2003 04:00:00 GMT`.
+///
+/// Podcasts get this wrong in many ways, so the weekday is ignored, month
+/// and day names may be spelled out, seconds and the zone may be missing,
+/// years may have two digits and the zone may contain a colon.
+pub fn parse_rfc2822(text: &str) -> Option<i64> {
+    let text = text.replace(',', " ");
+    let mut parts = text.split_whitespace().peekable();
+
+    // the weekday is optional and not worth validating, a month name
+    // starts a "Jun 10" date instead.
+    let first = *parts.peek()?;
+    if first.chars().next()?.is_alphabetic() && parse_month(first).is_none() {
+        parts.next();
+    }
+
+    let first = parts.next()?;
+    let second = parts.next()?;
+    // "10 Jun" is the standard, "Jun 10" shows up too.
+    let (day, month) = match (first.parse::<u32>(), parse_month(second)) {
+        (Ok(day), Some(month)) => (day, month),
+        _ => (second.parse().ok()?, parse_month(first)?),
+    };
+
+    let year: i64 = match parts.next()?.parse().ok()? {
+        year @ 0..=49 => 2000 + year,
+        year @ 50..=99 => 1900 + year,
+        year => year,
+    };
+
+    let (hour, minute, second) = match parts.next() {
+        Some(time) => parse_time(time)?,
+        None => (0, 0, 0),
+    };
+
+    let offset = match parts.next() {
+        Some(zone) => parse_zone(zone)?,
+        None => 0,
+    };
+
+    timestamp(year, month, day, hour, minute, second, offset)
+}
+
+/// Parse an RFC 3339 date such as `2003-12-13T18:30:02Z`, a plain date or a
+/// space instead of the `T` are accepted as well.
+pub fn parse_rfc3339(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (date, rest) = match text.find(['T', 't', ' ']) {
+        Some(idx) => (&text[..idx], text[idx + 1..].trim()),
+        None => (text, ""),
+    };
+
+    let mut date_parts = date.split('-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    if date_parts.next().is_some() {
+        return None;
+    }
+
+    if rest.is_empty() {
+        return timestamp(year, month, day, 0, 0, 0, 0);
+    }
+
+    let zone_start = rest.find(['Z', 'z', '+', '-']).unwrap_or(rest.len());
+    let (time, zone) = rest.split_at(zone_start);
+
+    // drop the fractional seconds.
+    let time = time.split('.').next()?;
+    let (hour, minute, second) = parse_time(time)?;
+
+    let offset = if zone.is_empty() {
+        0
+    } else {
+        parse_zone(zone)?
+    };
+
+    timestamp(year, month, day, hour, minute, second, offset)
+}
+
+/// Format a timestamp as `YYYY-MM-DD` (UTC).
+pub fn format_date(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format a timestamp as `YYYY-MM-DD HH:MM` (UTC).
+pub fn format_datetime(timestamp: i64) -> String {
+    let seconds = timestamp.rem_euclid(86400);
+    format!(
+        "{} {:02}:{:02}",
+        format_date(timestamp),
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// The current time as a unix timestamp.
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn parse_month(text: &str) -> Option<u32> {
+    let text = text.to_lowercase();
+    if text.len() < 3 {
+        return None;
+    }
+    MONTHS
+        .iter()
+        .position(|month| text.starts_with(month))
+        .map(|idx| idx as u32 + 1)
+}
+
+fn parse_time(text: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = text.split(':');
+    let hour = parts.next()?.parse().ok()?;
+    let minute = parts.next()?.parse().ok()?;
+    let second = match parts.next() {
+        Some(second) => second.parse().ok()?,
+        None => 0,
+    };
+
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    Some((hour, minute, second))
+}
+
+/// The offset of a zone from UTC in seconds.
+fn parse_zone(text: &str) -> Option<i64> {
+    let hours = match text.to_uppercase().as_str() {
+        "GMT" | "UT" | "UTC" | "Z" => Some(0),
+        "EST" => Some(-5),
+        "EDT" => Some(-4),
+        "CST" => Some(-6),
+        "CDT" => Some(-5),
+        "MST" => Some(-7),
+        "MDT" => Some(-6),
+        "PST" => Some(-8),
+        "PDT" => Some(-7),
+        _ => None,
+    };
+    if let Some(hours) = hours {
+        return Some(hours * 3600);
+    }
+
+    let sign = match text.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+
+    let digits: String = text[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+fn timestamp(
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    offset: i64,
+) -> Option<i64> {
+    // feeds are untrusted, far off years would overflow the arithmetic.
+    if !(1..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    Some(seconds - offset)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[test]
+fn test_parse_rfc2822() {
+    // 2003-06-10 04:00:00 UTC
+    let expected = Some(1055217600);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 04:00:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 04:00:00 +0000"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 00:00:00 EDT"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 06:00:00 +0200"), expected);
+}
+
+#[test]
+fn test_parse_rfc2822_malformed() {
+    let expected = Some(1055217600);
+    assert_eq!(parse_rfc2822("10 Jun 2003 04:00:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Tue 10 Jun 2003 04:00:00 GMT"), expected);
+    assert_eq!(
+        parse_rfc2822("Tuesday, 10 June 2003 04:00:00 GMT"),
+        expected
+    );
+    assert_eq!(parse_rfc2822("tue,  10 jun 2003  04:00:00 gmt"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 04:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 04:00:00"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 03 04:00:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 06:00:00 +02:00"), expected);
+    assert_eq!(parse_rfc2822("Tue, Jun 10 2003 04:00:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Jun 10 2003 04:00:00 GMT"), expected);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003"), Some(1055203200));
+    assert_eq!(
+        parse_rfc2822("Sun, 01 Sept 2019 10:00:00 GMT"),
+        Some(1567332000)
+    );
+}
+
+#[test]
+fn test_parse_rfc2822_invalid() {
+    assert_eq!(parse_rfc2822(""), None);
+    assert_eq!(parse_rfc2822("myfeed item1 pubdate"), None);
+    assert_eq!(parse_rfc2822("Tue, 31 Jun 2003 04:00:00 GMT"), None);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 25:00:00 GMT"), None);
+    assert_eq!(parse_rfc2822("Tue, 10 Jun 2003 04:00:00 XYZ"), None);
+    assert_eq!(
+        parse_rfc2822("Tue, 10 Jun 99999999999999999 00:00 GMT"),
+        None
+    );
+    assert_eq!(parse_rfc3339("99999999999999999-06-10"), None);
+}
+
+#[test]
+fn test_parse_rfc3339() {
+    assert_eq!(parse_rfc3339("2003-12-13T18:30:02Z"), Some(1071340202));
+    assert_eq!(parse_rfc3339("2003-12-13T18:30:02.25Z"), Some(1071340202));
+    assert_eq!(parse_rfc3339("2003-12-13T19:30:02+01:00"), Some(1071340202));
+    assert_eq!(parse_rfc3339("2003-12-13T13:30:02-05:00"), Some(1071340202));
+    assert_eq!(parse_rfc3339("2003-12-13 18:30:02"), Some(1071340202));
+    assert_eq!(parse_rfc3339("2003-12-13"), Some(1071273600));
+    assert_eq!(parse_rfc3339("2003-13-13T18:30:02Z"), None);
+    assert_eq!(parse_rfc3339("yesterday"), None);
+}
+
+#[test]
+fn test_parse_date_falls_back() {
+    assert_eq!(parse_date("2003-12-13T18:30:02Z"), Some(1071340202));
+    assert_eq!(
+        parse_date("Tue, 10 Jun 2003 04:00:00 GMT"),
+        Some(1055217600)
+    );
+}
+
+#[test]
+fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(1071340202), "2003-12-13");
+    assert_eq!(format_date(951782400), "2000-02-29");
+    assert_eq!(format_date(-86400), "1969-12-31");
+    assert_eq!(format_datetime(1071340202), "2003-12-13 18:30");
+}
@@ -1,4 +1,10 @@
-use rusqlite::{params, Connection, Result};
+use crate::date;
+use crate::feed::{Channel, Chapters, Funding, Person, Soundbite, Transcript};
+use crate::retention::FeedRules;
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
 
 pub struct Database {
     connection: Connection,
@@ -9,41 +15,558 @@ pub struct Feed {
     pub id: u32,
     pub url: String,
     pub name: String,
+    pub title: String,
+    pub link: String,
+    pub description: String,
+    pub image: String,
+    pub language: String,
+    pub author: String,
+    /// seconds between automatic refreshes, `None` uses the global setting.
+    pub refresh_interval: Option<u32>,
+    /// unix timestamp of the last successful fetch.
+    pub last_refreshed_at: Option<i64>,
+    /// `ETag` and `Last-Modified` of the last response, empty when the
+    /// server sent none.
+    pub etag: String,
+    pub last_modified: String,
+    /// HTTP Basic credentials, empty for public feeds.
+    pub username: String,
+    pub password: String,
+    pub rules: FeedRules,
+}
+
+const FEED_COLUMNS: &str = "id, url, name, title, link, description, image, language, author, \
+    refresh_interval, last_refreshed_at, etag, last_modified, username, password, \
+    auto_download, delete_played, keep_episodes, keep_bytes";
+
+fn feed_from_row(row: &rusqlite::Row) -> Feed {
+    Feed {
+        id: row.get(0).unwrap_or(0),
+        url: row.get(1).unwrap_or(String::from("")),
+        name: row.get(2).unwrap_or(String::from("")),
+        title: row.get(3).unwrap_or(String::from("")),
+        link: row.get(4).unwrap_or(String::from("")),
+        description: row.get(5).unwrap_or(String::from("")),
+        image: row.get(6).unwrap_or(String::from("")),
+        language: row.get(7).unwrap_or(String::from("")),
+        author: row.get(8).unwrap_or(String::from("")),
+        refresh_interval: row.get(9).unwrap_or(None),
+        last_refreshed_at: row.get(10).unwrap_or(None),
+        etag: row.get(11).unwrap_or(String::from("")),
+        last_modified: row.get(12).unwrap_or(String::from("")),
+        username: row.get(13).unwrap_or(String::from("")),
+        password: row.get(14).unwrap_or(String::from("")),
+        rules: FeedRules {
+            auto_download: row.get(15).unwrap_or(0),
+            delete_played: row.get(16).unwrap_or(false),
+            keep_episodes: row.get(17).unwrap_or(None),
+            keep_bytes: row
+                .get::<_, Option<i64>>(18)
+                .unwrap_or(None)
+                .map(|bytes| bytes as u64),
+        },
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PlaybackState {
+    pub episode_id: u32,
+    /// seconds into the episode.
+    pub position: f64,
+    pub duration: f64,
+    pub last_played_at: i64,
+    pub played: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadState {
+    Queued,
+    Downloading,
+    Done,
+    Failed,
+    /// removed by a retention rule.
+    Deleted,
+}
+
+impl DownloadState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DownloadState::Queued => "queued",
+            DownloadState::Downloading => "downloading",
+            DownloadState::Done => "done",
+            DownloadState::Failed => "failed",
+            DownloadState::Deleted => "deleted",
+        }
+    }
+
+    fn from_str(state: &str) -> Self {
+        match state {
+            "queued" => DownloadState::Queued,
+            "downloading" => DownloadState::Downloading,
+            "done" => DownloadState::Done,
+            "deleted" => DownloadState::Deleted,
+            _ => DownloadState::Failed,
+        }
+    }
+}
+
+/// An episode downloaded, or being downloaded, to `path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+    pub episode_id: u32,
+    pub state: DownloadState,
+    pub path: String,
+    /// bytes written so far.
+    pub bytes: u64,
+    /// size of the file, when the server told it.
+    pub total: Option<u64>,
+    /// why the last attempt failed.
+    pub error: String,
 }
 
 #[derive(Debug)]
 pub struct Episode {
     pub id: u32,
+    pub guid: String,
     pub url: String,
     pub title: String,
     pub description: String,
+    /// publication date as a unix timestamp.
+    pub pubdate: Option<i64>,
+    pub duration: Option<u32>,
+    pub episode: Option<u32>,
+    pub season: Option<u32>,
+    pub episode_type: String,
+    pub explicit: bool,
+    pub image: String,
+    pub feed_id: u32,
 }
 
-impl Database {
-    pub fn new() -> Result<Self> {
-        // let conn = Connection::open_in_memory()?;
-        let conn = Connection::open("./db.sqlite")?;
+const EPISODE_COLUMNS: &str = "id, url, title, description, duration, episode, season, \
+    episode_type, explicit, image, feed_id, guid, pubdate";
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS feeds (
-               id              INTEGER PRIMARY KEY,
-               url             TEXT NOT NULL,
-               name            TEXT NOT NULL
-            )",
-            [],
-        )?;
+fn episode_from_row(row: &rusqlite::Row) -> Episode {
+    Episode {
+        id: row.get(0).unwrap_or(0),
+        url: row.get(1).unwrap_or(String::from("")),
+        title: row.get(2).unwrap_or(String::from("")),
+        description: row.get(3).unwrap_or(String::from("")),
+        duration: row.get(4).unwrap_or(None),
+        episode: row.get(5).unwrap_or(None),
+        season: row.get(6).unwrap_or(None),
+        episode_type: row.get(7).unwrap_or(String::from("full")),
+        explicit: row.get(8).unwrap_or(false),
+        image: row.get(9).unwrap_or(String::from("")),
+        feed_id: row.get(10).unwrap_or(0),
+        guid: row.get(11).unwrap_or(String::from("")),
+        pubdate: row.get(12).unwrap_or(None),
+    }
+}
+
+/// Schema changes in the order they were made, a database at
+/// `user_version` n has had the first n applied.
+/// Released migrations never change, new columns and tables go into a new
+/// one at the end.
+const MIGRATIONS: &[fn(&Transaction) -> Result<()>] = &[
+    migrate_initial,
+    migrate_metadata,
+    migrate_foreign_keys,
+    migrate_downloads,
+    migrate_feed_rules,
+];
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS episodes (
+#[derive(Debug)]
+pub enum OpenError {
+    Sqlite(rusqlite::Error),
+    /// the database was written by a newer minicast.
+    TooNew {
+        version: u32,
+        supported: u32,
+    },
+}
+
+impl From<rusqlite::Error> for OpenError {
+    fn from(e: rusqlite::Error) -> Self {
+        OpenError::Sqlite(e)
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::Sqlite(e) => write!(f, "{}", e),
+            OpenError::TooNew { version, supported } => write!(
+                f,
+                "database version {} is newer than this minicast supports ({}), please upgrade",
+                version, supported
+            ),
+        }
+    }
+}
+
+fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Bring the schema up to date, each migration in its own transaction.
+fn migrate(conn: &mut Connection) -> Result<(), OpenError> {
+    let supported = MIGRATIONS.len() as u32;
+    let version = schema_version(conn)?;
+    if version > supported {
+        return Err(OpenError::TooNew { version, supported });
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> Result<bool> {
+    tx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )
+    .map(|count: u32| count > 0)
+}
+
+/// Replace `table` with one created from `definition`, keeping the rows
+/// matching `filter`. It's the only way sqlite has to add constraints.
+fn rebuild_table(tx: &Transaction, table: &str, definition: &str, filter: &str) -> Result<()> {
+    tx.execute_batch(&format!("CREATE TABLE {}_new ({})", table, definition))?;
+
+    let mut statement = tx.prepare("SELECT name FROM pragma_table_info(?1) ORDER BY cid")?;
+    let columns = statement
+        .query_map(params![format!("{}_new", table)], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?
+        .join(", ");
+
+    tx.execute_batch(&format!(
+        "INSERT INTO {table}_new ({columns}) SELECT {columns} FROM {table} WHERE {filter};
+         DROP TABLE {table};
+         ALTER TABLE {table}_new RENAME TO {table};",
+        table = table,
+        columns = columns,
+        filter = filter
+    ))
+}
+
+/// Databases from before migrations may already have some of the columns.
+fn add_column(tx: &Transaction, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !has_column(tx, table, column)? {
+        tx.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
+}
+
+/// The schema minicast started with.
+fn migrate_initial(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS feeds (
+           id              INTEGER PRIMARY KEY,
+           url             TEXT NOT NULL,
+           name            TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS episodes (
+           id              INTEGER PRIMARY KEY,
+           url             TEXT NOT NULL,
+           title           TEXT NOT NULL,
+           description     TEXT NOT NULL,
+           feed_id         INTEGER
+        );",
+    )
+}
+
+/// Channel and episode metadata, playback state, moved feeds and the
+/// queue.
+fn migrate_metadata(tx: &Transaction) -> Result<()> {
+    let feed_columns = [
+        ("title", "TEXT NOT NULL DEFAULT ''"),
+        ("link", "TEXT NOT NULL DEFAULT ''"),
+        ("description", "TEXT NOT NULL DEFAULT ''"),
+        ("image", "TEXT NOT NULL DEFAULT ''"),
+        ("language", "TEXT NOT NULL DEFAULT ''"),
+        ("author", "TEXT NOT NULL DEFAULT ''"),
+        ("podcast_guid", "TEXT NOT NULL DEFAULT ''"),
+        ("refresh_interval", "INTEGER"),
+        ("last_refreshed_at", "INTEGER"),
+        ("etag", "TEXT NOT NULL DEFAULT ''"),
+        ("last_modified", "TEXT NOT NULL DEFAULT ''"),
+        ("username", "TEXT NOT NULL DEFAULT ''"),
+        ("password", "TEXT NOT NULL DEFAULT ''"),
+    ];
+    for (column, definition) in &feed_columns {
+        add_column(tx, "feeds", column, definition)?;
+    }
+
+    // episodes are unique per guid, a constraint sqlite can only add by
+    // copying the table. The feed parser falls back to the enclosure url
+    // for the guid, so old episodes keep matching on refresh.
+    if !has_column(tx, "episodes", "guid")? {
+        tx.execute_batch(
+            "CREATE TABLE episodes_new (
                id              INTEGER PRIMARY KEY,
+               guid            TEXT NOT NULL,
                url             TEXT NOT NULL,
                title           TEXT NOT NULL,
                description     TEXT NOT NULL,
-               feed_id         INTEGER
-            )",
-            [],
+               pubdate         INTEGER,
+               duration        INTEGER,
+               episode         INTEGER,
+               season          INTEGER,
+               episode_type    TEXT NOT NULL DEFAULT 'full',
+               explicit        INTEGER NOT NULL DEFAULT 0,
+               image           TEXT NOT NULL DEFAULT '',
+               feed_id         INTEGER,
+               UNIQUE (feed_id, guid)
+            );
+            INSERT OR IGNORE INTO episodes_new (id, guid, url, title, description, feed_id)
+               SELECT id, url, url, title, description, feed_id FROM episodes;
+            DROP TABLE episodes;
+            ALTER TABLE episodes_new RENAME TO episodes;",
         )?;
+    }
+    let episode_columns = [
+        ("pubdate", "INTEGER"),
+        ("duration", "INTEGER"),
+        ("episode", "INTEGER"),
+        ("season", "INTEGER"),
+        ("episode_type", "TEXT NOT NULL DEFAULT 'full'"),
+        ("explicit", "INTEGER NOT NULL DEFAULT 0"),
+        ("image", "TEXT NOT NULL DEFAULT ''"),
+    ];
+    for (column, definition) in &episode_columns {
+        add_column(tx, "episodes", column, definition)?;
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS chapters (
+           id              INTEGER PRIMARY KEY,
+           episode_id      INTEGER NOT NULL,
+           url             TEXT NOT NULL,
+           type            TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS transcripts (
+           id              INTEGER PRIMARY KEY,
+           episode_id      INTEGER NOT NULL,
+           url             TEXT NOT NULL,
+           type            TEXT NOT NULL,
+           language        TEXT NOT NULL,
+           rel             TEXT NOT NULL
+        );
+        -- channel level persons have no episode_id.
+        CREATE TABLE IF NOT EXISTS persons (
+           id              INTEGER PRIMARY KEY,
+           feed_id         INTEGER NOT NULL,
+           episode_id      INTEGER,
+           name            TEXT NOT NULL,
+           role            TEXT NOT NULL,
+           person_group    TEXT NOT NULL,
+           image           TEXT NOT NULL,
+           href            TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS funding (
+           id              INTEGER PRIMARY KEY,
+           feed_id         INTEGER NOT NULL,
+           url             TEXT NOT NULL,
+           message         TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS soundbites (
+           id              INTEGER PRIMARY KEY,
+           episode_id      INTEGER NOT NULL,
+           start_time      REAL NOT NULL,
+           duration        REAL NOT NULL,
+           title           TEXT NOT NULL
+        );
+        -- positions are in seconds, last_played_at is a unix timestamp.
+        CREATE TABLE IF NOT EXISTS playback_state (
+           episode_id      INTEGER PRIMARY KEY,
+           position        REAL NOT NULL,
+           duration        REAL NOT NULL,
+           last_played_at  INTEGER NOT NULL,
+           played          INTEGER NOT NULL DEFAULT 0
+        );
+        -- every url a feed moved away from.
+        CREATE TABLE IF NOT EXISTS feed_url_history (
+           id              INTEGER PRIMARY KEY,
+           feed_id         INTEGER NOT NULL,
+           old_url         TEXT NOT NULL,
+           new_url         TEXT NOT NULL,
+           changed_at      INTEGER NOT NULL
+        );
+        -- the up next queue, played from the lowest position.
+        CREATE TABLE IF NOT EXISTS queue (
+           episode_id      INTEGER PRIMARY KEY,
+           position        INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Child rows go away with their feed or episode, and the columns they are
+/// looked up by get indices. Orphans left by older versions are dropped.
+fn migrate_foreign_keys(tx: &Transaction) -> Result<()> {
+    let episode = "episode_id IN (SELECT id FROM episodes)";
+    let feed = "feed_id IN (SELECT id FROM feeds)";
+
+    rebuild_table(
+        tx,
+        "episodes",
+        "id              INTEGER PRIMARY KEY,
+         guid            TEXT NOT NULL,
+         url             TEXT NOT NULL,
+         title           TEXT NOT NULL,
+         description     TEXT NOT NULL,
+         pubdate         INTEGER,
+         duration        INTEGER,
+         episode         INTEGER,
+         season          INTEGER,
+         episode_type    TEXT NOT NULL DEFAULT 'full',
+         explicit        INTEGER NOT NULL DEFAULT 0,
+         image           TEXT NOT NULL DEFAULT '',
+         feed_id         INTEGER REFERENCES feeds (id) ON DELETE CASCADE,
+         UNIQUE (feed_id, guid)",
+        feed,
+    )?;
+    rebuild_table(
+        tx,
+        "chapters",
+        "id              INTEGER PRIMARY KEY,
+         episode_id      INTEGER NOT NULL REFERENCES episodes (id) ON DELETE CASCADE,
+         url             TEXT NOT NULL,
+         type            TEXT NOT NULL",
+        episode,
+    )?;
+    rebuild_table(
+        tx,
+        "transcripts",
+        "id              INTEGER PRIMARY KEY,
+         episode_id      INTEGER NOT NULL REFERENCES episodes (id) ON DELETE CASCADE,
+         url             TEXT NOT NULL,
+         type            TEXT NOT NULL,
+         language        TEXT NOT NULL,
+         rel             TEXT NOT NULL",
+        episode,
+    )?;
+    rebuild_table(
+        tx,
+        "persons",
+        "id              INTEGER PRIMARY KEY,
+         feed_id         INTEGER NOT NULL REFERENCES feeds (id) ON DELETE CASCADE,
+         episode_id      INTEGER REFERENCES episodes (id) ON DELETE CASCADE,
+         name            TEXT NOT NULL,
+         role            TEXT NOT NULL,
+         person_group    TEXT NOT NULL,
+         image           TEXT NOT NULL,
+         href            TEXT NOT NULL",
+        &format!("{} AND (episode_id IS NULL OR {})", feed, episode),
+    )?;
+    rebuild_table(
+        tx,
+        "funding",
+        "id              INTEGER PRIMARY KEY,
+         feed_id         INTEGER NOT NULL REFERENCES feeds (id) ON DELETE CASCADE,
+         url             TEXT NOT NULL,
+         message         TEXT NOT NULL",
+        feed,
+    )?;
+    rebuild_table(
+        tx,
+        "soundbites",
+        "id              INTEGER PRIMARY KEY,
+         episode_id      INTEGER NOT NULL REFERENCES episodes (id) ON DELETE CASCADE,
+         start_time      REAL NOT NULL,
+         duration        REAL NOT NULL,
+         title           TEXT NOT NULL",
+        episode,
+    )?;
+    rebuild_table(
+        tx,
+        "playback_state",
+        "episode_id      INTEGER PRIMARY KEY REFERENCES episodes (id) ON DELETE CASCADE,
+         position        REAL NOT NULL,
+         duration        REAL NOT NULL,
+         last_played_at  INTEGER NOT NULL,
+         played          INTEGER NOT NULL DEFAULT 0",
+        episode,
+    )?;
+    rebuild_table(
+        tx,
+        "feed_url_history",
+        "id              INTEGER PRIMARY KEY,
+         feed_id         INTEGER NOT NULL REFERENCES feeds (id) ON DELETE CASCADE,
+         old_url         TEXT NOT NULL,
+         new_url         TEXT NOT NULL,
+         changed_at      INTEGER NOT NULL",
+        feed,
+    )?;
+    rebuild_table(
+        tx,
+        "queue",
+        "episode_id      INTEGER PRIMARY KEY REFERENCES episodes (id) ON DELETE CASCADE,
+         position        INTEGER NOT NULL",
+        episode,
+    )?;
+
+    // (feed_id, guid) is already indexed by its unique constraint.
+    tx.execute_batch(
+        "CREATE INDEX episodes_pubdate ON episodes (feed_id, pubdate);
+        CREATE INDEX chapters_episode ON chapters (episode_id);
+        CREATE INDEX transcripts_episode ON transcripts (episode_id);
+        CREATE INDEX persons_feed ON persons (feed_id, episode_id);
+        CREATE INDEX persons_episode ON persons (episode_id);
+        CREATE INDEX funding_feed ON funding (feed_id);
+        CREATE INDEX soundbites_episode ON soundbites (episode_id);
+        CREATE INDEX feed_url_history_feed ON feed_url_history (feed_id);",
+    )
+}
 
+/// Episodes downloaded for offline playback.
+fn migrate_downloads(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE downloads (
+           episode_id      INTEGER PRIMARY KEY REFERENCES episodes (id) ON DELETE CASCADE,
+           state           TEXT NOT NULL,
+           path            TEXT NOT NULL,
+           bytes           INTEGER NOT NULL DEFAULT 0,
+           total           INTEGER,
+           error           TEXT NOT NULL DEFAULT ''
+        );",
+    )
+}
+
+/// Automatic downloads and retention, per feed.
+fn migrate_feed_rules(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE feeds ADD COLUMN auto_download INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE feeds ADD COLUMN delete_played INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE feeds ADD COLUMN keep_episodes INTEGER;
+        ALTER TABLE feeds ADD COLUMN keep_bytes INTEGER;",
+    )
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self, OpenError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, OpenError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, OpenError> {
+        // foreign keys are off while migrating, tables get replaced.
+        migrate(&mut conn)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        /*
         let feeds = [
             ("Laracasts", "https://feeds.simplecast.com/sY509q85"),
             ("TED Talks Daily", "https://www.ted.com/feeds/talks.rss"),
@@ -51,7 +574,6 @@ impl Database {
             ("Invisibllia", "https://feeds.npr.org/510307/podcast.xml"),
         ];
 
-        /*
         for (name, url) in feeds {
             conn.execute(
                 "INSERT INTO feeds (url, name) VALUES (?1, ?2)",
@@ -63,20 +585,31 @@ impl Database {
         Ok(Self { connection: conn })
     }
 
+    /// Run `f` in a transaction, rolled back when it fails. Inside another
+    /// transaction `f` becomes part of it.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        if !self.connection.is_autocommit() {
+            return f();
+        }
+        let tx = self.connection.unchecked_transaction()?;
+        let result = f()?;
+        tx.commit()?;
+        Ok(result)
+    }
+
     pub fn get_episode(&self, id: u32) -> std::result::Result<Episode, String> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id, url, title, description FROM episodes WHERE id = ?1")
+            .prepare(&format!(
+                "SELECT {} FROM episodes WHERE id = ?1",
+                EPISODE_COLUMNS
+            ))
             .expect("couldn't run query");
 
-        let row = stmt.query_row(params![id], |row| {
-            Ok(Episode {
-                id: row.get(0).unwrap_or(0),
-                url: row.get(1).unwrap_or(String::from("")),
-                title: row.get(2).unwrap_or(String::from("")),
-                description: row.get(3).unwrap_or(String::from("")),
-            })
-        });
+        let row = stmt.query_row(params![id], |row| Ok(episode_from_row(row)));
 
         match row {
             Ok(episode) => Ok(episode),
@@ -95,16 +628,10 @@ impl Database {
     pub fn get_feed(&self, id: u32) -> std::result::Result<Feed, String> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id, url, name FROM feeds WHERE id = ?1")
+            .prepare(&format!("SELECT {} FROM feeds WHERE id = ?1", FEED_COLUMNS))
             .expect("couldn't run query");
 
-        let row = stmt.query_row(params![id], |row| {
-            Ok(Feed {
-                id: row.get(0).unwrap_or(0),
-                url: row.get(1).unwrap_or(String::from("")),
-                name: row.get(2).unwrap_or(String::from("")),
-            })
-        });
+        let row = stmt.query_row(params![id], |row| Ok(feed_from_row(row)));
 
         match row {
             Ok(feed) => Ok(feed),
@@ -112,27 +639,96 @@ impl Database {
         }
     }
 
+    pub fn set_last_refreshed(&self, feed_id: u32, timestamp: i64) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET last_refreshed_at = ?1 WHERE id = ?2",
+            params![timestamp, feed_id],
+        )?;
+        Ok(())
+    }
+
+    /// Point a feed to a new url, keeping the old one in its history.
+    pub fn set_feed_url(&self, feed_id: u32, url: &str) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO feed_url_history (feed_id, old_url, new_url, changed_at)
+             SELECT id, url, ?1, ?2 FROM feeds WHERE id = ?3 AND url != ?1",
+            params![url, date::now(), feed_id],
+        )?;
+        self.connection.execute(
+            "UPDATE feeds SET url = ?1 WHERE id = ?2",
+            params![url, feed_id],
+        )?;
+        Ok(())
+    }
+
+    /// Old and new url of every move of a feed, oldest first.
+    pub fn get_url_history(&self, feed_id: u32) -> Vec<(String, String)> {
+        let mut stmt = self
+            .connection
+            .prepare(
+                "SELECT old_url, new_url FROM feed_url_history
+                 WHERE feed_id = ?1 ORDER BY id",
+            )
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![feed_id], |row| Ok((row.get(0)?, row.get(1)?)));
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set_credentials(&self, feed_id: u32, username: &str, password: &str) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET username = ?1, password = ?2 WHERE id = ?3",
+            params![username, password, feed_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_cache_headers(&self, feed_id: u32, etag: &str, last_modified: &str) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET etag = ?1, last_modified = ?2 WHERE id = ?3",
+            params![etag, last_modified, feed_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_refresh_interval(&self, feed_id: u32, interval: Option<u32>) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET refresh_interval = ?1 WHERE id = ?2",
+            params![interval, feed_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_feed_rules(&self, feed_id: u32, rules: &FeedRules) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET auto_download = ?2, delete_played = ?3, keep_episodes = ?4,
+                keep_bytes = ?5
+             WHERE id = ?1",
+            params![
+                feed_id,
+                rules.auto_download,
+                rules.delete_played,
+                rules.keep_episodes,
+                rules.keep_bytes.map(|bytes| bytes as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
     pub fn get_feeds(&self) -> Vec<Feed> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id, url, name FROM feeds")
+            .prepare(&format!("SELECT {} FROM feeds", FEED_COLUMNS))
             .expect("couldn't run query");
 
-        let rows = stmt.query_map([], |row| {
-            Ok(Feed {
-                id: row.get(0).unwrap_or(0),
-                url: row.get(1).unwrap_or(String::from("")),
-                name: row.get(2).unwrap_or(String::from("")),
-            })
-        });
+        let rows = stmt.query_map([], |row| Ok(feed_from_row(row)));
 
         match rows {
-            Ok(map) => map
-                .filter_map(|it| match it {
-                    Ok(row) => Some(row),
-                    _ => None,
-                })
-                .collect(),
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
             _ => Vec::new(),
         }
     }
@@ -140,33 +736,34 @@ impl Database {
     pub fn get_episodes(&self, feed_id: u32) -> Vec<Episode> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id, url, title, description, feed_id FROM episodes WHERE feed_id = ?1")
+            .prepare(&format!(
+                "SELECT {} FROM episodes WHERE feed_id = ?1 ORDER BY pubdate DESC, id",
+                EPISODE_COLUMNS
+            ))
             .expect("couldn't run query");
 
-        let rows = stmt.query_map(params![feed_id], |row| {
-            Ok(Episode {
-                id: row.get(0).unwrap_or(0),
-                url: row.get(1).unwrap_or(String::from("")),
-                title: row.get(2).unwrap_or(String::from("")),
-                description: row.get(3).unwrap_or(String::from("")),
-            })
-        });
+        let rows = stmt.query_map(params![feed_id], |row| Ok(episode_from_row(row)));
 
         match rows {
-            Ok(map) => map
-                .filter_map(|it| match it {
-                    Ok(row) => Some(row),
-                    _ => None,
-                })
-                .collect(),
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
             _ => Vec::new(),
         }
     }
 
+    pub fn count_episodes(&self, feed_id: u32) -> u32 {
+        self.connection
+            .query_row(
+                "SELECT COUNT(*) FROM episodes WHERE feed_id = ?1",
+                params![feed_id],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
+    }
+
+    /// Delete a feed, everything belonging to it goes with it.
     pub fn delete_feed(&self, feed_id: u32) -> Result<()> {
         self.connection
             .execute("DELETE from feeds WHERE id = ?1", params![feed_id])?;
-        self.clear_episodes(feed_id)?;
         Ok(())
     }
 
@@ -176,13 +773,891 @@ impl Database {
         Ok(())
     }
 
-    pub fn set_episodes(&self, feed_id: u32, episodes: Vec<Episode>) -> Result<()> {
+    /// Merge fetched episodes into a feed, matching them on their guid:
+    /// new ones are inserted, known ones updated in place so their ids stay
+    /// the same. Returns the ids in the same order as `episodes`.
+    pub fn merge_episodes(&self, feed_id: u32, episodes: Vec<Episode>) -> Result<Vec<u32>> {
+        self.transaction(|| self.merge_episodes_unchecked(feed_id, episodes))
+    }
+
+    fn merge_episodes_unchecked(&self, feed_id: u32, episodes: Vec<Episode>) -> Result<Vec<u32>> {
+        let mut ids = Vec::with_capacity(episodes.len());
         for episode in episodes {
+            let existing: Option<u32> = self
+                .connection
+                .prepare_cached("SELECT id FROM episodes WHERE feed_id = ?1 AND guid = ?2")?
+                .query_row(params![feed_id, episode.guid], |row| row.get(0))
+                .optional()?;
+
+            let params = params![
+                episode.url,
+                episode.title,
+                episode.description,
+                episode.duration,
+                episode.episode,
+                episode.season,
+                episode.episode_type,
+                episode.explicit,
+                episode.image,
+                feed_id,
+                episode.guid,
+                episode.pubdate
+            ];
+
+            match existing {
+                Some(id) => {
+                    self.connection
+                        .prepare_cached(
+                            "UPDATE episodes SET url = ?1, title = ?2, description = ?3,
+                                duration = ?4, episode = ?5, season = ?6, episode_type = ?7,
+                                explicit = ?8, image = ?9, pubdate = ?12
+                             WHERE feed_id = ?10 AND guid = ?11",
+                        )?
+                        .execute(params)?;
+                    ids.push(id);
+                }
+                None => {
+                    self.connection
+                        .prepare_cached(
+                            "INSERT INTO episodes (url, title, description, duration, episode,
+                                season, episode_type, explicit, image, feed_id, guid, pubdate)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                        )?
+                        .execute(params)?;
+                    ids.push(self.connection.last_insert_rowid() as u32);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Store the channel metadata of a feed, a feed added without a name
+    /// takes the channel title.
+    pub fn set_channel(&self, feed_id: u32, channel: &Channel) -> Result<()> {
+        self.connection.execute(
+            "UPDATE feeds SET title = ?1, link = ?2, description = ?3, image = ?4,
+                language = ?5, author = ?6, podcast_guid = ?7
+             WHERE id = ?8",
+            params![
+                channel.title,
+                channel.link,
+                channel.description,
+                channel.image,
+                channel.language,
+                channel.author,
+                channel.guid,
+                feed_id
+            ],
+        )?;
+        self.connection.execute(
+            "UPDATE feeds SET name = title WHERE id = ?1 AND name = ''",
+            params![feed_id],
+        )?;
+        self.set_funding(feed_id, &channel.funding)?;
+        self.set_persons(feed_id, None, &channel.persons)?;
+        Ok(())
+    }
+
+    pub fn set_chapters(&self, episode_id: u32, chapters: &Option<Chapters>) -> Result<()> {
+        self.connection.execute(
+            "DELETE from chapters WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        if let Some(chapters) = chapters {
             self.connection.execute(
-                "INSERT INTO episodes (url, title, description, feed_id) VALUES (?1, ?2, ?3, ?4)",
-                params![episode.url, episode.title, episode.description, feed_id],
+                "INSERT INTO chapters (episode_id, url, type) VALUES (?1, ?2, ?3)",
+                params![episode_id, chapters.url, chapters.kind],
             )?;
         }
         Ok(())
     }
+
+    pub fn get_chapters(&self, episode_id: u32) -> Option<Chapters> {
+        self.connection
+            .query_row(
+                "SELECT url, type FROM chapters WHERE episode_id = ?1",
+                params![episode_id],
+                |row| {
+                    Ok(Chapters {
+                        url: row.get(0)?,
+                        kind: row.get(1)?,
+                    })
+                },
+            )
+            .ok()
+    }
+
+    pub fn set_transcripts(&self, episode_id: u32, transcripts: &[Transcript]) -> Result<()> {
+        self.connection.execute(
+            "DELETE from transcripts WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        for transcript in transcripts {
+            self.connection.execute(
+                "INSERT INTO transcripts (episode_id, url, type, language, rel)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    episode_id,
+                    transcript.url,
+                    transcript.kind,
+                    transcript.language,
+                    transcript.rel
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_transcripts(&self, episode_id: u32) -> Vec<Transcript> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT url, type, language, rel FROM transcripts WHERE episode_id = ?1")
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![episode_id], |row| {
+            Ok(Transcript {
+                url: row.get(0)?,
+                kind: row.get(1)?,
+                language: row.get(2)?,
+                rel: row.get(3)?,
+            })
+        });
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replace the persons of an episode, or of the channel when
+    /// `episode_id` is `None`.
+    pub fn set_persons(
+        &self,
+        feed_id: u32,
+        episode_id: Option<u32>,
+        persons: &[Person],
+    ) -> Result<()> {
+        self.connection.execute(
+            "DELETE from persons WHERE feed_id = ?1 AND episode_id IS ?2",
+            params![feed_id, episode_id],
+        )?;
+        for person in persons {
+            self.connection.execute(
+                "INSERT INTO persons (feed_id, episode_id, name, role, person_group, image, href)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    feed_id,
+                    episode_id,
+                    person.name,
+                    person.role,
+                    person.group,
+                    person.image,
+                    person.href
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_persons(&self, feed_id: u32, episode_id: Option<u32>) -> Vec<Person> {
+        let mut stmt = self
+            .connection
+            .prepare(
+                "SELECT name, role, person_group, image, href FROM persons
+                 WHERE feed_id = ?1 AND episode_id IS ?2",
+            )
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![feed_id, episode_id], |row| {
+            Ok(Person {
+                name: row.get(0)?,
+                role: row.get(1)?,
+                group: row.get(2)?,
+                image: row.get(3)?,
+                href: row.get(4)?,
+            })
+        });
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set_funding(&self, feed_id: u32, funding: &[Funding]) -> Result<()> {
+        self.connection
+            .execute("DELETE from funding WHERE feed_id = ?1", params![feed_id])?;
+        for fund in funding {
+            self.connection.execute(
+                "INSERT INTO funding (feed_id, url, message) VALUES (?1, ?2, ?3)",
+                params![feed_id, fund.url, fund.message],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_funding(&self, feed_id: u32) -> Vec<Funding> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT url, message FROM funding WHERE feed_id = ?1")
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![feed_id], |row| {
+            Ok(Funding {
+                url: row.get(0)?,
+                message: row.get(1)?,
+            })
+        });
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set_soundbites(&self, episode_id: u32, soundbites: &[Soundbite]) -> Result<()> {
+        self.connection.execute(
+            "DELETE from soundbites WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        for soundbite in soundbites {
+            self.connection.execute(
+                "INSERT INTO soundbites (episode_id, start_time, duration, title)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    episode_id,
+                    soundbite.start,
+                    soundbite.duration,
+                    soundbite.title
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_soundbites(&self, episode_id: u32) -> Vec<Soundbite> {
+        let mut stmt = self
+            .connection
+            .prepare(
+                "SELECT start_time, duration, title FROM soundbites
+                 WHERE episode_id = ?1 ORDER BY start_time",
+            )
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![episode_id], |row| {
+            Ok(Soundbite {
+                start: row.get(0)?,
+                duration: row.get(1)?,
+                title: row.get(2)?,
+            })
+        });
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set_playback_state(&self, episode_id: u32, position: f64, duration: f64) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO playback_state (episode_id, position, duration, last_played_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (episode_id) DO UPDATE SET position = ?2, duration = ?3,
+                last_played_at = ?4",
+            params![episode_id, position, duration, date::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Mark an episode (un)played, its saved position is left alone.
+    pub fn set_played(&self, episode_id: u32, played: bool) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO playback_state (episode_id, position, duration, last_played_at, played)
+             VALUES (?1, 0, 0, ?2, ?3)
+             ON CONFLICT (episode_id) DO UPDATE SET played = ?3",
+            params![episode_id, date::now(), played],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_played(&self, episode_id: u32) -> bool {
+        self.connection
+            .query_row(
+                "SELECT played FROM playback_state WHERE episode_id = ?1",
+                params![episode_id],
+                |row| row.get(0),
+            )
+            .unwrap_or(false)
+    }
+
+    /// Ids of the played episodes of a feed.
+    pub fn get_played_episodes(&self, feed_id: u32) -> Vec<u32> {
+        let mut stmt = self
+            .connection
+            .prepare(
+                "SELECT episodes.id FROM episodes
+                 JOIN playback_state ON playback_state.episode_id = episodes.id
+                 WHERE episodes.feed_id = ?1 AND playback_state.played = 1",
+            )
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map(params![feed_id], |row| row.get(0));
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Number of unplayed episodes per feed id, feeds without any are left out.
+    pub fn get_unplayed_counts(&self) -> HashMap<u32, u32> {
+        let mut stmt = self
+            .connection
+            .prepare(
+                "SELECT episodes.feed_id, COUNT(*) FROM episodes
+                 LEFT JOIN playback_state ON playback_state.episode_id = episodes.id
+                 WHERE IFNULL(playback_state.played, 0) = 0
+                 GROUP BY episodes.feed_id",
+            )
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)));
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    pub fn get_playback_state(&self, episode_id: u32) -> Option<PlaybackState> {
+        self.connection
+            .query_row(
+                "SELECT episode_id, position, duration, last_played_at, played FROM playback_state
+                 WHERE episode_id = ?1",
+                params![episode_id],
+                |row| {
+                    Ok(PlaybackState {
+                        episode_id: row.get(0)?,
+                        position: row.get(1)?,
+                        duration: row.get(2)?,
+                        last_played_at: row.get(3)?,
+                        played: row.get(4)?,
+                    })
+                },
+            )
+            .ok()
+    }
+
+    /// Episode ids in the queue, in play order.
+    pub fn get_queue(&self) -> Vec<u32> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT episode_id FROM queue ORDER BY position")
+            .expect("couldn't run query");
+
+        let rows = stmt.query_map([], |row| row.get(0));
+
+        match rows {
+            Ok(map) => map.filter_map(|it| it.ok()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Append an episode to the queue, an episode already queued keeps
+    /// its place.
+    pub fn enqueue(&self, episode_id: u32) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO queue (episode_id, position)
+             VALUES (?1, (SELECT IFNULL(MAX(position), 0) + 1 FROM queue))",
+            params![episode_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn dequeue(&self, episode_id: u32) -> Result<()> {
+        self.connection.execute(
+            "DELETE from queue WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        Ok(())
+    }
+
+    /// Replace the whole queue, used to reorder it.
+    pub fn set_queue(&self, episode_ids: &[u32]) -> Result<()> {
+        self.clear_queue()?;
+        for (position, episode_id) in episode_ids.iter().enumerate() {
+            self.connection.execute(
+                "INSERT INTO queue (episode_id, position) VALUES (?1, ?2)",
+                params![episode_id, position as u32 + 1],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_queue(&self) -> Result<()> {
+        self.connection.execute("DELETE from queue", [])?;
+        Ok(())
+    }
+
+    /// Start tracking the download of an episode to `path`.
+    pub fn set_download_queued(&self, episode_id: u32, path: &str) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO downloads (episode_id, state, path) VALUES (?1, ?2, ?3)
+             ON CONFLICT (episode_id) DO UPDATE SET state = ?2, path = ?3, error = ''",
+            params![episode_id, DownloadState::Queued.as_str(), path],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_download_state(&self, episode_id: u32, state: DownloadState) -> Result<()> {
+        self.connection.execute(
+            "UPDATE downloads SET state = ?2 WHERE episode_id = ?1",
+            params![episode_id, state.as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_download_progress(
+        &self,
+        episode_id: u32,
+        bytes: u64,
+        total: Option<u64>,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE downloads SET bytes = ?2, total = ?3 WHERE episode_id = ?1",
+            params![episode_id, bytes as i64, total.map(|total| total as i64)],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_download_failed(&self, episode_id: u32, error: &str) -> Result<()> {
+        self.connection.execute(
+            "UPDATE downloads SET state = ?2, error = ?3 WHERE episode_id = ?1",
+            params![episode_id, DownloadState::Failed.as_str(), error],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_download(&self, episode_id: u32) -> Result<()> {
+        self.connection.execute(
+            "DELETE FROM downloads WHERE episode_id = ?1",
+            params![episode_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_download(&self, episode_id: u32) -> Option<Download> {
+        self.query_downloads("WHERE episode_id = ?1", params![episode_id])
+            .pop()
+    }
+
+    /// Downloads of the episodes of a feed.
+    pub fn get_feed_downloads(&self, feed_id: u32) -> Vec<Download> {
+        self.query_downloads(
+            "WHERE episode_id IN (SELECT id FROM episodes WHERE feed_id = ?1)",
+            params![feed_id],
+        )
+    }
+
+    /// Downloads that were queued or running when minicast last stopped.
+    pub fn get_unfinished_downloads(&self) -> Vec<Download> {
+        self.query_downloads(
+            "WHERE state IN (?1, ?2)",
+            params![
+                DownloadState::Queued.as_str(),
+                DownloadState::Downloading.as_str()
+            ],
+        )
+    }
+
+    fn query_downloads(&self, filter: &str, params: &[&dyn rusqlite::ToSql]) -> Vec<Download> {
+        let mut statement = self
+            .connection
+            .prepare(&format!(
+                "SELECT episode_id, state, path, bytes, total, error FROM downloads {}",
+                filter
+            ))
+            .unwrap();
+        statement
+            .query_map(params, |row| {
+                let state: String = row.get(1)?;
+                let bytes: i64 = row.get(3)?;
+                let total: Option<i64> = row.get(4)?;
+                Ok(Download {
+                    episode_id: row.get(0)?,
+                    state: DownloadState::from_str(&state),
+                    path: row.get(2)?,
+                    bytes: bytes as u64,
+                    total: total.map(|total| total as u64),
+                    error: row.get(5)?,
+                })
+            })
+            .unwrap()
+            .filter_map(|download| download.ok())
+            .collect()
+    }
+}
+
+/// An episode whose enclosure is `http://example.com/<guid>.mp3`.
+#[cfg(test)]
+pub fn test_episode(guid: &str, title: &str) -> Episode {
+    Episode {
+        id: 0,
+        guid: guid.into(),
+        url: format!("http://example.com/{}.mp3", guid),
+        title: title.into(),
+        description: String::new(),
+        pubdate: None,
+        duration: None,
+        episode: None,
+        season: None,
+        episode_type: "full".into(),
+        explicit: false,
+        image: String::new(),
+        feed_id: 0,
+    }
+}
+
+/// A feed with `count` episodes, in a fresh database their ids are
+/// 1..=count.
+#[cfg(test)]
+pub fn seed_episodes(db: &Database, count: u32) -> Vec<u32> {
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+    let episodes = (1..=count)
+        .map(|i| test_episode(&i.to_string(), &i.to_string()))
+        .collect();
+    db.merge_episodes(feed_id, episodes).unwrap()
+}
+
+#[test]
+fn test_merge_episodes_keeps_ids() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+
+    let ids = db
+        .merge_episodes(
+            feed_id,
+            vec![test_episode("a", "first"), test_episode("b", "second")],
+        )
+        .unwrap();
+
+    // "a" changed its title, "c" is new.
+    let merged = db
+        .merge_episodes(
+            feed_id,
+            vec![
+                test_episode("c", "third"),
+                test_episode("a", "first, fixed"),
+                test_episode("b", "second"),
+            ],
+        )
+        .unwrap();
+
+    assert_eq!(merged[1..], ids[..]);
+    assert!(!ids.contains(&merged[0]));
+    assert_eq!(db.get_episode(ids[0]).unwrap().title, "first, fixed");
+    assert_eq!(db.get_episodes(feed_id).len(), 3);
+}
+
+#[test]
+fn test_merge_episodes_per_feed() {
+    let db = Database::in_memory().unwrap();
+    let first = db.create_feed("first".into(), "url".into()).unwrap();
+    let second = db.create_feed("second".into(), "url".into()).unwrap();
+
+    // the same guid in two feeds is two episodes.
+    let a = db
+        .merge_episodes(first, vec![test_episode("a", "a")])
+        .unwrap();
+    let b = db
+        .merge_episodes(second, vec![test_episode("a", "a")])
+        .unwrap();
+    assert_ne!(a, b);
+}
+
+#[test]
+fn test_get_episodes_newest_first() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+
+    let dated = |guid: &str, pubdate: Option<i64>| Episode {
+        pubdate,
+        ..test_episode(guid, guid)
+    };
+    db.merge_episodes(
+        feed_id,
+        vec![
+            dated("old", Some(100)),
+            dated("undated", None),
+            dated("new", Some(300)),
+            dated("middle", Some(200)),
+        ],
+    )
+    .unwrap();
+
+    let guids: Vec<String> = db
+        .get_episodes(feed_id)
+        .into_iter()
+        .map(|episode| episode.guid)
+        .collect();
+    assert_eq!(guids, vec!["new", "middle", "old", "undated"]);
+}
+
+#[test]
+fn test_playback_state_upsert() {
+    let db = Database::in_memory().unwrap();
+    seed_episodes(&db, 1);
+    assert_eq!(db.get_playback_state(1), None);
+
+    db.set_playback_state(1, 10.0, 3600.0).unwrap();
+    db.set_playback_state(1, 25.5, 3600.0).unwrap();
+
+    let state = db.get_playback_state(1).unwrap();
+    assert_eq!(state.position, 25.5);
+    assert_eq!(state.duration, 3600.0);
+    assert!(state.last_played_at > 0);
+    assert!(!state.played);
+}
+
+#[test]
+fn test_played_survives_position_updates() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+    let ids = db
+        .merge_episodes(
+            feed_id,
+            vec![test_episode("a", "a"), test_episode("b", "b")],
+        )
+        .unwrap();
+
+    db.set_played(ids[0], true).unwrap();
+    db.set_playback_state(ids[0], 10.0, 100.0).unwrap();
+    assert!(db.is_played(ids[0]));
+    assert_eq!(db.get_playback_state(ids[0]).unwrap().position, 10.0);
+    assert_eq!(db.get_played_episodes(feed_id), vec![ids[0]]);
+    assert_eq!(db.get_unplayed_counts().get(&feed_id), Some(&1));
+
+    db.set_played(ids[0], false).unwrap();
+    assert!(!db.is_played(ids[0]));
+    assert_eq!(db.get_unplayed_counts().get(&feed_id), Some(&2));
+}
+
+#[test]
+fn test_queue() {
+    let db = Database::in_memory().unwrap();
+    seed_episodes(&db, 5);
+    db.enqueue(3).unwrap();
+    db.enqueue(1).unwrap();
+    db.enqueue(2).unwrap();
+    // already queued, stays where it is.
+    db.enqueue(3).unwrap();
+    assert_eq!(db.get_queue(), vec![3, 1, 2]);
+
+    db.dequeue(1).unwrap();
+    assert_eq!(db.get_queue(), vec![3, 2]);
+
+    db.set_queue(&[2, 3]).unwrap();
+    db.enqueue(5).unwrap();
+    assert_eq!(db.get_queue(), vec![2, 3, 5]);
+
+    db.clear_queue().unwrap();
+    assert!(db.get_queue().is_empty());
+}
+
+#[test]
+fn test_set_feed_url_keeps_history() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "http://old".into()).unwrap();
+
+    db.set_feed_url(feed_id, "http://new").unwrap();
+    // the same url again is not a move.
+    db.set_feed_url(feed_id, "http://new").unwrap();
+
+    assert_eq!(db.get_feed(feed_id).unwrap().url, "http://new");
+    assert_eq!(
+        db.get_url_history(feed_id),
+        vec![("http://old".to_string(), "http://new".to_string())]
+    );
+}
+
+#[test]
+fn test_migrate_baseline_database() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(include_str!("../fixtures/db_baseline.sql"))
+        .unwrap();
+    let db = Database::init(conn).unwrap();
+
+    assert_eq!(
+        schema_version(&db.connection).unwrap(),
+        MIGRATIONS.len() as u32
+    );
+
+    let feeds = db.get_feeds();
+    assert_eq!(feeds.len(), 2);
+    assert_eq!(feeds[0].name, "Laracasts");
+    assert_eq!(feeds[0].refresh_interval, None);
+    assert_eq!(feeds[0].etag, "");
+
+    let episodes = db.get_episodes(1);
+    assert_eq!(episodes.len(), 2);
+    for episode in &episodes {
+        assert_eq!(episode.guid, episode.url);
+        assert_eq!(episode.episode_type, "full");
+    }
+
+    // the migrated tables are usable.
+    db.enqueue(3).unwrap();
+    db.set_played(3, true).unwrap();
+    assert_eq!(db.get_unplayed_counts().get(&1), Some(&2));
+}
+
+#[test]
+fn test_migrate_is_idempotent() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn).unwrap();
+    conn.execute("INSERT INTO feeds (url, name) VALUES ('u', 'n')", [])
+        .unwrap();
+    migrate(&mut conn).unwrap();
+
+    let db = Database::init(conn).unwrap();
+    assert_eq!(db.get_feeds().len(), 1);
+}
+
+#[test]
+fn test_migrate_refuses_newer_database() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "user_version", 99).unwrap();
+    assert!(matches!(
+        Database::init(conn),
+        Err(OpenError::TooNew {
+            version: 99,
+            supported: _
+        })
+    ));
+}
+
+#[test]
+fn test_delete_feed_cascades() {
+    let db = Database::in_memory().unwrap();
+    let ids = seed_episodes(&db, 2);
+    let other = seed_episodes(&db, 1);
+    db.set_playback_state(ids[0], 10.0, 100.0).unwrap();
+    db.enqueue(ids[1]).unwrap();
+    db.enqueue(other[0]).unwrap();
+    db.set_feed_url(1, "moved").unwrap();
+
+    db.delete_feed(1).unwrap();
+    assert!(db.get_episodes(1).is_empty());
+    assert_eq!(db.get_playback_state(ids[0]), None);
+    assert_eq!(db.get_queue(), other);
+    assert!(db.get_url_history(1).is_empty());
+    assert_eq!(db.get_episodes(2).len(), 1);
+
+    // nothing can point at a deleted feed.
+    assert!(db.set_funding(1, &[Funding::default()]).is_err());
+}
+
+#[test]
+fn test_transaction_rolls_back() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+
+    // the second episode has no feed and fails, the first goes too.
+    let result = db.transaction(|| {
+        db.merge_episodes(feed_id, vec![test_episode("a", "a")])?;
+        db.merge_episodes(99, vec![test_episode("b", "b")])
+    });
+    assert!(result.is_err());
+    assert_eq!(db.count_episodes(feed_id), 0);
+}
+
+#[test]
+fn test_downloads() {
+    let db = Database::in_memory().unwrap();
+    let ids = seed_episodes(&db, 2);
+    assert_eq!(db.get_download(ids[0]), None);
+
+    db.set_download_queued(ids[0], "/tmp/1.mp3").unwrap();
+    db.set_download_queued(ids[1], "/tmp/2.mp3").unwrap();
+    db.set_download_state(ids[0], DownloadState::Downloading)
+        .unwrap();
+    db.set_download_progress(ids[0], 10, Some(100)).unwrap();
+    db.set_download_failed(ids[1], "HTTP 404").unwrap();
+
+    let download = db.get_download(ids[0]).unwrap();
+    assert_eq!(download.state, DownloadState::Downloading);
+    assert_eq!((download.bytes, download.total), (10, Some(100)));
+    assert_eq!(db.get_unfinished_downloads(), vec![download]);
+    assert_eq!(db.get_download(ids[1]).unwrap().error, "HTTP 404");
+
+    // queuing again clears the error.
+    db.set_download_queued(ids[1], "/tmp/2.mp3").unwrap();
+    assert_eq!(db.get_download(ids[1]).unwrap().error, "");
+    assert_eq!(db.get_feed_downloads(1).len(), 2);
+
+    db.delete_feed(1).unwrap();
+    assert_eq!(db.get_download(ids[0]), None);
+}
+
+#[test]
+fn test_feed_rules() {
+    let db = Database::in_memory().unwrap();
+    let feed_id = db.create_feed("feed".into(), "url".into()).unwrap();
+    assert!(db.get_feed(feed_id).unwrap().rules.is_empty());
+
+    let rules = FeedRules {
+        auto_download: 3,
+        delete_played: true,
+        keep_episodes: Some(5),
+        keep_bytes: Some(5_000_000_000),
+    };
+    db.set_feed_rules(feed_id, &rules).unwrap();
+    assert_eq!(db.get_feed(feed_id).unwrap().rules, rules);
+}
+
+/// Refreshing a large feed into a library on disk takes a fraction of a
+/// second. Losing the transaction or the guid index makes it take many.
+#[test]
+fn test_merge_5000_episodes_in_time() {
+    use std::time::{Duration, Instant};
+
+    let path = std::env::temp_dir().join(format!("minicast-merge-{}.sqlite", std::process::id()));
+    let db = Database::open(&path).unwrap();
+    let feed_id = db
+        .create_feed("bench".into(), "http://bench".into())
+        .unwrap();
+    let episodes = || {
+        (0..5000)
+            .map(|i| Episode {
+                id: 0,
+                guid: format!("guid-{}", i),
+                url: format!("http://bench/{}.mp3", i),
+                title: format!("Episode {}", i),
+                description: "a description".into(),
+                pubdate: Some(i),
+                duration: Some(3600),
+                episode: Some(i as u32),
+                season: None,
+                episode_type: "full".into(),
+                explicit: false,
+                image: "".into(),
+                feed_id,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // far above what it takes, even unoptimized on a busy machine.
+    let limit = Duration::from_secs(5);
+    for _ in 0..2 {
+        let start = Instant::now();
+        db.merge_episodes(feed_id, episodes()).unwrap();
+        assert!(start.elapsed() < limit);
+    }
+    assert_eq!(db.count_episodes(feed_id), 5000);
+    let start = Instant::now();
+    db.delete_feed(feed_id).unwrap();
+    assert!(start.elapsed() < limit);
+
+    drop(db);
+    std::fs::remove_file(path).unwrap();
 }
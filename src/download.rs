@@ -0,0 +1,468 @@
+use crate::controller::ControllerMessage;
+use crate::http;
+use crate::settings::NetworkSettings;
+use crate::tags::{self, TagError, Tags};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running download reports its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// An enclosure to download to `path`.
+#[derive(Debug, Clone)]
+pub struct DownloadRequest {
+    pub url: String,
+    pub path: PathBuf,
+    /// `Authorization` header for private feeds.
+    pub authorization: Option<String>,
+    pub network: NetworkSettings,
+    /// written into the file once it is complete.
+    pub tags: Option<Tags>,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Network(String),
+    HttpStatus(u16),
+    Io(io::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::Network(e) => write!(f, "network error: {}", e),
+            DownloadError::HttpStatus(code) => write!(f, "server responded with HTTP {}", code),
+            DownloadError::Io(e) => write!(f, "couldn't write the episode: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::Io(e)
+    }
+}
+
+impl From<http::Error> for DownloadError {
+    fn from(e: http::Error) -> Self {
+        DownloadError::Network(e.to_string())
+    }
+}
+
+/// A complete download, at its final path.
+#[derive(Debug)]
+pub struct Downloaded {
+    pub size: u64,
+    /// why the tags couldn't be written, the file plays without them.
+    pub tag_error: Option<TagError>,
+}
+
+/// Where a download is written until it is complete.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Download `request.url` to `request.path`, continuing a partial download
+/// with a `Range` request. `progress` gets the bytes written and the
+/// total size when the server tells it. The file is tagged before it gets
+/// its name, so a file at `request.path` is always a finished one.
+///
+/// A connection that stalls for longer than the read timeout is cut off:
+/// when the partial file got further than ever before, it picks up where
+/// it stopped without counting it as a retry.
+pub fn download<F>(request: &DownloadRequest, mut progress: F) -> Result<Downloaded, DownloadError>
+where
+    F: FnMut(u64, Option<u64>),
+{
+    if let Some(dir) = request.path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = partial_path(&request.path);
+    let partial_size = || fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+
+    let mut retries = 0;
+    // only getting further than ever before is progress: a server that
+    // ignores ranges starts over every time.
+    let mut furthest = partial_size();
+    loop {
+        let offset = partial_size();
+        match download_from(request, &partial, offset, &mut progress) {
+            Ok(Some(size)) => {
+                let tag_error = match &request.tags {
+                    Some(tags) => {
+                        let extension = request.path.extension().unwrap_or_default();
+                        let extension = extension.to_string_lossy();
+                        tags::write(&partial, &extension, tags, &request.network).err()
+                    }
+                    None => None,
+                };
+                fs::rename(&partial, &request.path)?;
+                return Ok(Downloaded { size, tag_error });
+            }
+            // the server closed the connection or stopped sending.
+            Ok(None) if partial_size() > furthest => {
+                furthest = partial_size();
+                retries = 0;
+            }
+            Ok(None) | Err(DownloadError::Network(_)) if retries < request.network.retries => {
+                thread::sleep(http::retry_delay(&request.network, retries));
+                retries += 1;
+            }
+            Ok(None) => return Err(DownloadError::Network("the download keeps stopping".into())),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// One request for the bytes from `offset` on, appended to `partial`.
+/// `None` when it is worth another request: the response ended early
+/// after writing something, or the partial file had to be started over.
+fn download_from<F>(
+    request: &DownloadRequest,
+    partial: &Path,
+    offset: u64,
+    progress: &mut F,
+) -> Result<Option<u64>, DownloadError>
+where
+    F: FnMut(u64, Option<u64>),
+{
+    let authorization = request.authorization.as_deref();
+    let (response, _) = http::follow(&request.url, authorization, &request.network, |http| {
+        if offset > 0 {
+            http.set("Range", &format!("bytes={}-", offset))
+        } else {
+            http
+        }
+    })?;
+
+    let (mut file, mut written, total) = match response.status() {
+        206 => {
+            let (first, total) = response
+                .header("content-range")
+                .map(parse_content_range)
+                .unwrap_or_default();
+            // not the bytes that were asked for.
+            if first != Some(offset) {
+                File::create(partial)?;
+                return Ok(None);
+            }
+            let file = OpenOptions::new().append(true).open(partial)?;
+            (file, offset, total)
+        }
+        // no range support, start over.
+        200 => {
+            let total = response
+                .header("content-length")
+                .and_then(|length| length.parse().ok());
+            (File::create(partial)?, 0, total)
+        }
+        // the partial file has every byte, unless it is larger than the
+        // episode has become.
+        416 if offset > 0 => {
+            let (_, total) = response
+                .header("content-range")
+                .map(parse_content_range)
+                .unwrap_or_default();
+            if total == Some(offset) {
+                return Ok(Some(offset));
+            }
+            File::create(partial)?;
+            return Ok(None);
+        }
+        code => return Err(DownloadError::HttpStatus(code)),
+    };
+
+    let start = written;
+    let mut body = response.into_reader();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut last_progress = Instant::now();
+    progress(written, total);
+
+    loop {
+        let read = match body.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) if written > start => return Ok(None),
+            Err(e) => return Err(DownloadError::Network(e.to_string())),
+        };
+        file.write_all(&buffer[..read])?;
+        written += read as u64;
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            progress(written, total);
+        }
+    }
+
+    match total {
+        Some(total) if written < total => {
+            if written > start {
+                Ok(None)
+            } else {
+                Err(DownloadError::Network("the connection closed early".into()))
+            }
+        }
+        _ => {
+            progress(written, total);
+            Ok(Some(written))
+        }
+    }
+}
+
+/// The first byte and the total size of a `Content-Range`, like
+/// `bytes 8-12/13`, or `bytes */13` which has no first byte.
+fn parse_content_range(range: &str) -> (Option<u64>, Option<u64>) {
+    let range = range.trim().strip_prefix("bytes ").unwrap_or_default();
+    let (bytes, total) = range.split_once('/').unwrap_or((range, "*"));
+    let first = bytes
+        .split_once('-')
+        .and_then(|(first, _)| first.trim().parse().ok());
+    (first, total.trim().parse().ok())
+}
+
+struct Job {
+    episode_id: u32,
+    request: DownloadRequest,
+}
+
+/// A pool of threads downloading episodes, reporting back to the
+/// controller as `Download*` messages.
+pub struct Downloader {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Downloader {
+    /// Start `workers` threads, the number of downloads running at once.
+    pub fn new(controller_tx: mpsc::Sender<ControllerMessage>, workers: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..workers.max(1) {
+            let rx = Arc::clone(&rx);
+            let controller_tx = controller_tx.clone();
+            thread::spawn(move || loop {
+                // the lock is released as soon as a job is taken.
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+
+                let id = job.episode_id;
+                if controller_tx
+                    .send(ControllerMessage::DownloadStarted(id))
+                    .is_err()
+                {
+                    break;
+                }
+
+                let result = download(&job.request, |bytes, total| {
+                    let _ =
+                        controller_tx.send(ControllerMessage::DownloadProgress(id, bytes, total));
+                });
+                let (message, tag_error) = match result {
+                    Ok(downloaded) => (
+                        ControllerMessage::DownloadFinished(id),
+                        downloaded.tag_error,
+                    ),
+                    Err(e) => (ControllerMessage::DownloadFailed(id, e.to_string()), None),
+                };
+
+                if controller_tx.send(message).is_err() {
+                    break;
+                }
+
+                // the file plays without its tags, a failure is only reported.
+                if let Some(e) = tag_error {
+                    let _ = controller_tx.send(ControllerMessage::TaggingFailed(id, e.to_string()));
+                }
+            });
+        }
+
+        Downloader { jobs }
+    }
+
+    pub fn download(&self, episode_id: u32, request: DownloadRequest) {
+        self.jobs
+            .send(Job {
+                episode_id,
+                request,
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+fn test_request(url: String, name: &str) -> DownloadRequest {
+    let dir = std::env::temp_dir().join(format!("minicast-download-{}", std::process::id()));
+    DownloadRequest {
+        url,
+        path: dir.join(name),
+        authorization: None,
+        network: NetworkSettings {
+            retries: 0,
+            ..NetworkSettings::default()
+        },
+        tags: None,
+    }
+}
+
+#[test]
+fn test_download() {
+    let (url, requests) = http::serve(vec![http::http_response("200 OK", &[], "episode audio")]);
+    let request = test_request(url, "full.mp3");
+
+    let mut reported = vec![];
+    let downloaded = download(&request, |bytes, total| reported.push((bytes, total))).unwrap();
+    assert_eq!(downloaded.size, 13);
+    assert_eq!(reported.last(), Some(&(13, Some(13))));
+    assert_eq!(fs::read_to_string(&request.path).unwrap(), "episode audio");
+    assert!(!partial_path(&request.path).exists());
+    assert!(!requests.recv().unwrap().contains("Range"));
+
+    fs::remove_file(&request.path).unwrap();
+}
+
+#[test]
+fn test_download_tags_before_rename() {
+    use id3::TagLike;
+
+    let (url, _requests) = http::serve(vec![http::http_response("200 OK", &[], "episode audio")]);
+    let mut request = test_request(url, "tagged.mp3");
+    request.tags = Some(Tags {
+        album: "The Show".into(),
+        title: "Episode 12".into(),
+        ..Tags::default()
+    });
+
+    let downloaded = download(&request, |_, _| {}).unwrap();
+    assert!(downloaded.tag_error.is_none());
+    let tag = id3::Tag::read_from_path(&request.path).unwrap();
+    assert_eq!(tag.title(), Some("Episode 12"));
+    assert!(!partial_path(&request.path).exists());
+
+    fs::remove_file(&request.path).unwrap();
+}
+
+#[test]
+fn test_download_resumes() {
+    let (url, requests) = http::serve(vec![
+        http::http_response(
+            "206 Partial Content",
+            &["Content-Range: bytes 8-12/13"],
+            "audio",
+        ),
+        http::http_response("200 OK", &[], "episode audio"),
+    ]);
+
+    // the server sends the rest.
+    let request = test_request(url.clone(), "resume.mp3");
+    fs::create_dir_all(request.path.parent().unwrap()).unwrap();
+    fs::write(partial_path(&request.path), "episode ").unwrap();
+    assert_eq!(download(&request, |_, _| {}).unwrap().size, 13);
+    assert!(requests.recv().unwrap().contains("Range: bytes=8-"));
+    assert_eq!(fs::read_to_string(&request.path).unwrap(), "episode audio");
+    fs::remove_file(&request.path).unwrap();
+
+    // the server ignores the range and sends everything.
+    let request = test_request(url, "restart.mp3");
+    fs::write(partial_path(&request.path), "garbage").unwrap();
+    assert_eq!(download(&request, |_, _| {}).unwrap().size, 13);
+    assert_eq!(fs::read_to_string(&request.path).unwrap(), "episode audio");
+    fs::remove_file(&request.path).unwrap();
+}
+
+#[test]
+fn test_download_checks_ranges() {
+    let (url, requests) = http::serve(vec![
+        // another range than the one asked for.
+        http::http_response(
+            "206 Partial Content",
+            &["Content-Range: bytes 0-12/13"],
+            "episode audio",
+        ),
+        http::http_response("200 OK", &[], "episode audio"),
+        // the episode got shorter than the partial file.
+        http::http_response(
+            "416 Range Not Satisfiable",
+            &["Content-Range: bytes */7"],
+            "",
+        ),
+        http::http_response("200 OK", &[], "episode"),
+        http::http_response(
+            "416 Range Not Satisfiable",
+            &["Content-Range: bytes */13"],
+            "",
+        ),
+    ]);
+
+    let mut request = test_request(url, "ranges.mp3");
+    request.network.retries = 1;
+    request.network.retry_delay = 1;
+    fs::create_dir_all(request.path.parent().unwrap()).unwrap();
+    for expected in ["episode audio", "episode"] {
+        fs::write(partial_path(&request.path), "episode ").unwrap();
+        download(&request, |_, _| {}).unwrap();
+        assert!(requests.recv().unwrap().contains("Range: bytes=8-"));
+        assert!(!requests.recv().unwrap().contains("Range"));
+        assert_eq!(fs::read_to_string(&request.path).unwrap(), expected);
+        fs::remove_file(&request.path).unwrap();
+    }
+
+    // every byte is there already.
+    fs::write(partial_path(&request.path), "episode audio").unwrap();
+    assert_eq!(download(&request, |_, _| {}).unwrap().size, 13);
+    fs::remove_file(&request.path).unwrap();
+}
+
+#[test]
+fn test_download_ignored_range_gives_up() {
+    // a server that ignores ranges and always stops after "episode ".
+    let cut = "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nepisode ".to_string();
+    let (url, requests) = http::serve(vec![cut.clone(), cut.clone(), cut]);
+
+    let mut request = test_request(url, "ignored.mp3");
+    request.network.retries = 1;
+    request.network.retry_delay = 1;
+    assert!(matches!(
+        download(&request, |_, _| {}),
+        Err(DownloadError::Network(_))
+    ));
+    assert_eq!(requests.iter().count(), 3);
+    assert!(!request.path.exists());
+    fs::remove_file(partial_path(&request.path)).unwrap();
+}
+
+#[test]
+fn test_download_redirect_drops_credentials() {
+    let (other, other_requests) =
+        http::serve(vec![http::http_response("200 OK", &[], "episode audio")]);
+    // another host for the same test server.
+    let location = format!("Location: {}", other.replace("127.0.0.1", "localhost"));
+    let (url, requests) = http::serve(vec![http::http_response("302 Found", &[&location], "")]);
+
+    let mut request = test_request(url, "redirected.mp3");
+    request.authorization = Some("Basic dXNlcjpzZWNyZXQ=".into());
+    assert_eq!(download(&request, |_, _| {}).unwrap().size, 13);
+    assert!(requests.recv().unwrap().contains("Authorization"));
+    assert!(!other_requests.recv().unwrap().contains("Authorization"));
+    fs::remove_file(&request.path).unwrap();
+}
+
+#[test]
+fn test_download_http_status() {
+    let (url, _requests) = http::serve(vec![http::http_response("404 Not Found", &[], "")]);
+    let request = test_request(url, "missing.mp3");
+    assert!(matches!(
+        download(&request, |_, _| {}),
+        Err(DownloadError::HttpStatus(404))
+    ));
+    assert!(!request.path.exists());
+}
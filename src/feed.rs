@@ -1,29 +1,566 @@
 #![allow(dead_code)]
-extern crate minreq;
+extern crate base64;
 extern crate roxmltree;
 
+use crate::date;
+use crate::http;
+use crate::settings::NetworkSettings;
+use std::fmt;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+const ITUNES_NS: &str = "http://www.itunes.com/dtds/podcast-1.0.dtd";
+const PODCAST_NS: &str = "https://podcastindex.org/namespace/1.0";
+
 pub enum Feed {
     Url(String),
     Path(PathBuf),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// A fetch of a feed over http, with the validators of the previous
+/// response so an unchanged feed isn't downloaded again.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedRequest {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub network: NetworkSettings,
+}
+
+/// HTTP Basic credentials of a private feed, kept apart from its url.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Value of the `Authorization` header.
+    pub fn authorization(&self) -> String {
+        let pair = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", base64::encode(pair))
+    }
+}
+
+/// Split the `user:password@` part off a url, so it isn't stored or shown
+/// with the url.
+pub fn split_credentials(url: &str) -> (String, Option<Credentials>) {
+    let scheme_end = match url.find("://") {
+        Some(idx) => idx + 3,
+        None => return (url.into(), None),
+    };
+    let authority_end = url[scheme_end..]
+        .find(['/', '?', '#'])
+        .map(|idx| idx + scheme_end)
+        .unwrap_or(url.len());
+
+    let at = match url[scheme_end..authority_end].rfind('@') {
+        Some(idx) => idx + scheme_end,
+        None => return (url.into(), None),
+    };
+
+    let (username, password) = match url[scheme_end..at].split_once(':') {
+        Some((username, password)) => (username, password),
+        None => (&url[scheme_end..at], ""),
+    };
+    let credentials = Credentials {
+        username: username.into(),
+        password: password.into(),
+    };
+    (
+        format!("{}{}", &url[..scheme_end], &url[at + 1..]),
+        Some(credentials),
+    )
+}
+
+/// A url safe to show: no credentials and no query, where token urls keep
+/// their secret.
+pub fn redact_url(url: &str) -> String {
+    let (url, _) = split_credentials(url);
+    match url.find('?') {
+        Some(idx) => format!("{}?…", &url[..idx]),
+        None => url,
+    }
+}
+
+impl FeedRequest {
+    pub fn new(url: impl Into<String>) -> Self {
+        FeedRequest {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Fetched {
+    /// the server answered 304, what we have is still current.
+    NotModified,
+    Modified {
+        channel: Box<Channel>,
+        episodes: Vec<Episode>,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+pub enum FeedError {
+    Network(String),
+    HttpStatus(u16),
+    /// 401 or 403, the feed needs (other) credentials.
+    Unauthorized(u16),
+    InvalidXml {
+        message: String,
+        row: u32,
+        col: u32,
+    },
+    /// the document is neither an rss nor an atom feed.
+    NotRss,
+    UnsupportedVersion(String),
+    NoChannel,
+    /// the channel has no title, rss requires one.
+    NoChannelTitle,
+    /// the item at this position, from 1, has neither a title nor a
+    /// description.
+    InvalidItem(usize),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeedError::Network(e) => write!(f, "network error: {}", e),
+            FeedError::HttpStatus(code) => write!(f, "server responded with HTTP {}", code),
+            FeedError::Unauthorized(code) => write!(
+                f,
+                "access denied (HTTP {}), check the feed credentials",
+                code
+            ),
+            FeedError::InvalidXml { message, row, col } => {
+                write!(f, "invalid xml at {}:{}: {}", row, col, message)
+            }
+            FeedError::NotRss => write!(f, "not an rss or atom feed"),
+            FeedError::UnsupportedVersion(version) if version.is_empty() => {
+                write!(f, "rss version is missing")
+            }
+            FeedError::UnsupportedVersion(version) => {
+                write!(f, "unsupported rss version {}", version)
+            }
+            FeedError::NoChannel => write!(f, "rss feed has no channel"),
+            FeedError::NoChannelTitle => write!(f, "rss channel has no title"),
+            FeedError::InvalidItem(position) => write!(
+                f,
+                "rss item {} has neither a title nor a description",
+                position
+            ),
+            FeedError::Io(e) => write!(f, "couldn't read feed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FeedError {}
+
+impl From<std::io::Error> for FeedError {
+    fn from(e: std::io::Error) -> Self {
+        FeedError::Io(e)
+    }
+}
+
+impl From<http::Error> for FeedError {
+    fn from(e: http::Error) -> Self {
+        FeedError::Network(e.to_string())
+    }
+}
+
+impl From<roxmltree::Error> for FeedError {
+    fn from(e: roxmltree::Error) -> Self {
+        let pos = e.pos();
+        FeedError::InvalidXml {
+            message: e.to_string(),
+            row: pos.row,
+            col: pos.col,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum EpisodeType {
+    #[default]
+    Full,
+    Trailer,
+    Bonus,
+}
+
+impl EpisodeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EpisodeType::Full => "full",
+            EpisodeType::Trailer => "trailer",
+            EpisodeType::Bonus => "bonus",
+        }
+    }
+}
+
+impl From<&str> for EpisodeType {
+    fn from(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "trailer" => EpisodeType::Trailer,
+            "bonus" => EpisodeType::Bonus,
+            _ => EpisodeType::Full,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Episode {
+    /// `guid` of an item or `id` of an entry, the enclosure url when missing.
+    pub guid: String,
     pub url: String,
     pub title: String,
     pub description: String,
+    /// `pubDate` of an item or `published`/`updated` of an entry, as a unix timestamp.
+    pub published: Option<i64>,
+    /// duration in seconds.
+    pub duration: Option<u32>,
+    pub episode: Option<u32>,
+    pub season: Option<u32>,
+    pub episode_type: EpisodeType,
+    pub explicit: bool,
+    pub image: String,
+    pub chapters: Option<Chapters>,
+    pub transcripts: Vec<Transcript>,
+    pub persons: Vec<Person>,
+    pub soundbites: Vec<Soundbite>,
+}
+
+/// Channel level data, shared by every episode of a feed.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Channel {
+    pub title: String,
+    pub link: String,
+    pub description: String,
+    pub image: String,
+    pub language: String,
+    pub author: String,
+    /// `podcast:guid`, the globally unique id of the show.
+    pub guid: String,
+    pub funding: Vec<Funding>,
+    pub persons: Vec<Person>,
+    /// `itunes:new-feed-url`, where the publisher moved the feed to.
+    pub new_feed_url: String,
+}
+
+/// `podcast:chapters`, a link to the chapters file of an episode.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Chapters {
+    pub url: String,
+    pub kind: String,
+}
+
+/// `podcast:transcript`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Transcript {
+    pub url: String,
+    pub kind: String,
+    pub language: String,
+    pub rel: String,
+}
+
+/// `podcast:person`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Person {
+    pub name: String,
+    pub role: String,
+    pub group: String,
+    pub image: String,
+    pub href: String,
+}
+
+/// `podcast:funding`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Funding {
+    pub url: String,
+    pub message: String,
+}
+
+/// `podcast:soundbite`, start and duration are in seconds.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Soundbite {
+    pub start: f64,
+    pub duration: f64,
+    pub title: String,
 }
 
 impl Episode {
     fn new<T: Into<String>>(title: T, description: T, url: T) -> Self {
+        let url = url.into();
         Episode {
+            guid: url.clone(),
             title: title.into(),
             description: description.into(),
-            url: url.into(),
+            url,
+            ..Default::default()
+        }
+    }
+}
+
+/// Tags from the itunes namespace, found both on channels and items.
+#[derive(Debug, Default, Clone)]
+struct ITunes {
+    duration: Option<u32>,
+    episode: Option<u32>,
+    season: Option<u32>,
+    episode_type: Option<EpisodeType>,
+    explicit: Option<bool>,
+    image: Option<String>,
+    summary: Option<String>,
+    author: Option<String>,
+    new_feed_url: Option<String>,
+}
+
+impl ITunes {
+    fn parse_tag(&mut self, element: &roxmltree::Node) {
+        let text = get_element_text(element).trim();
+
+        match element.tag_name().name() {
+            "duration" => self.duration = parse_duration(text),
+            "episode" => self.episode = text.parse().ok(),
+            "season" => self.season = text.parse().ok(),
+            "episodeType" => self.episode_type = Some(EpisodeType::from(text)),
+            "explicit" => self.explicit = parse_explicit(text),
+            "image" => self.image = element.attribute("href").map(|href| href.into()),
+            "summary" => self.summary = Some(text.into()),
+            "author" => self.author = Some(text.into()),
+            "new-feed-url" => self.new_feed_url = Some(text.into()),
+            _ => (),
+        }
+    }
+
+    /// Copy the item tags into the episode, falling back to the channel
+    /// for the ones an item inherits.
+    fn apply(self, episode: &mut Episode, channel: &ITunes) {
+        if episode.description.is_empty() {
+            episode.description = self.summary.unwrap_or_default();
         }
+
+        episode.duration = self.duration;
+        episode.episode = self.episode;
+        episode.season = self.season;
+        episode.episode_type = self.episode_type.unwrap_or_default();
+        episode.explicit = self.explicit.or(channel.explicit).unwrap_or(false);
+        episode.image = self
+            .image
+            .or_else(|| channel.image.clone())
+            .unwrap_or_default();
+    }
+}
+
+/// Tags from the podcasting 2.0 namespace, found both on channels and items.
+#[derive(Debug, Default, Clone)]
+struct Podcast {
+    guid: Option<String>,
+    season: Option<u32>,
+    episode: Option<u32>,
+    chapters: Option<Chapters>,
+    transcripts: Vec<Transcript>,
+    persons: Vec<Person>,
+    funding: Vec<Funding>,
+    soundbites: Vec<Soundbite>,
+}
+
+impl Podcast {
+    fn parse_tag(&mut self, element: &roxmltree::Node) {
+        let text = get_element_text(element).trim();
+        let attribute = |name: &str| element.attribute(name).unwrap_or("").to_string();
+
+        match element.tag_name().name() {
+            "guid" => self.guid = Some(text.into()),
+            "season" => self.season = text.parse().ok(),
+            "episode" => self.episode = text.parse().ok(),
+            "chapters" => {
+                self.chapters = Some(Chapters {
+                    url: attribute("url"),
+                    kind: attribute("type"),
+                })
+            }
+            "transcript" => self.transcripts.push(Transcript {
+                url: attribute("url"),
+                kind: attribute("type"),
+                language: attribute("language"),
+                rel: attribute("rel"),
+            }),
+            "person" => self.persons.push(Person {
+                name: text.into(),
+                role: element.attribute("role").unwrap_or("host").into(),
+                group: element.attribute("group").unwrap_or("cast").into(),
+                image: attribute("img"),
+                href: attribute("href"),
+            }),
+            "funding" => self.funding.push(Funding {
+                url: attribute("url"),
+                message: text.into(),
+            }),
+            "soundbite" => {
+                let start = element.attribute("startTime").and_then(|v| v.parse().ok());
+                let duration = element.attribute("duration").and_then(|v| v.parse().ok());
+                if let (Some(start), Some(duration)) = (start, duration) {
+                    self.soundbites.push(Soundbite {
+                        start,
+                        duration,
+                        title: text.into(),
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Copy the item tags into the episode, the itunes season and episode
+    /// numbers take precedence when both are present.
+    fn apply(self, episode: &mut Episode) {
+        episode.season = episode.season.or(self.season);
+        episode.episode = episode.episode.or(self.episode);
+        episode.chapters = self.chapters;
+        episode.transcripts = self.transcripts;
+        episode.persons = self.persons;
+        episode.soundbites = self.soundbites;
+    }
+}
+
+fn parse_podcast_channel(element: &roxmltree::Node) -> Channel {
+    let mut podcast = Podcast::default();
+    element
+        .children()
+        .filter(|child| child.tag_name().namespace() == Some(PODCAST_NS))
+        .for_each(|child| podcast.parse_tag(&child));
+
+    Channel {
+        guid: podcast.guid.unwrap_or_default(),
+        funding: podcast.funding,
+        persons: podcast.persons,
+        ..Default::default()
+    }
+}
+
+fn parse_rss_channel(element: &roxmltree::Node, itunes: &ITunes) -> Channel {
+    let mut channel = parse_podcast_channel(element);
+    let mut image = String::new();
+    let mut managing_editor = String::new();
+
+    for child in element.children() {
+        if child.tag_name().namespace().is_some() {
+            continue;
+        }
+
+        match child.tag_name().name() {
+            "title" => channel.title = get_element_text(&child).trim().into(),
+            "link" => channel.link = get_element_text(&child).trim().into(),
+            "description" => channel.description = get_element_text(&child).trim().into(),
+            "language" => channel.language = get_element_text(&child).trim().into(),
+            "managingEditor" => managing_editor = get_element_text(&child).trim().into(),
+            "image" => {
+                if let Some(url) = child.children().find(|c| c.tag_name().name() == "url") {
+                    image = get_element_text(&url).trim().into();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // the itunes tags are usually the better ones for podcasts.
+    channel.image = itunes.image.clone().unwrap_or(image);
+    channel.author = itunes.author.clone().unwrap_or(managing_editor);
+    if channel.description.is_empty() {
+        channel.description = itunes.summary.clone().unwrap_or_default();
+    }
+    channel.new_feed_url = itunes.new_feed_url.clone().unwrap_or_default();
+
+    channel
+}
+
+fn parse_atom_channel(element: &roxmltree::Node, itunes: &ITunes) -> Channel {
+    let mut channel = parse_podcast_channel(element);
+    let mut logo = String::new();
+    let mut icon = String::new();
+
+    channel.language = element
+        .attribute(("http://www.w3.org/XML/1998/namespace", "lang"))
+        .unwrap_or("")
+        .into();
+
+    for child in element.children() {
+        if child.tag_name().namespace() != Some(ATOM_NS) {
+            continue;
+        }
+
+        match child.tag_name().name() {
+            "title" => channel.title = get_element_text(&child).trim().into(),
+            "subtitle" => channel.description = get_element_text(&child).trim().into(),
+            "logo" => logo = get_element_text(&child).trim().into(),
+            "icon" => icon = get_element_text(&child).trim().into(),
+            "link" => {
+                let rel = child.attribute("rel").unwrap_or("alternate");
+                if rel == "alternate" && channel.link.is_empty() {
+                    channel.link = child.attribute("href").unwrap_or("").into();
+                }
+            }
+            "author" => {
+                if let Some(name) = child.children().find(|c| c.tag_name().name() == "name") {
+                    channel.author = get_element_text(&name).trim().into();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if logo.is_empty() {
+        logo = icon;
+    }
+    channel.image = itunes.image.clone().unwrap_or(logo);
+    if let Some(author) = &itunes.author {
+        channel.author = author.clone();
+    }
+    channel.new_feed_url = itunes.new_feed_url.clone().unwrap_or_default();
+
+    channel
+}
+
+fn parse_itunes_channel(element: &roxmltree::Node) -> ITunes {
+    let mut itunes = ITunes::default();
+    element
+        .children()
+        .filter(|child| child.tag_name().namespace() == Some(ITUNES_NS))
+        .for_each(|child| itunes.parse_tag(&child));
+    itunes
+}
+
+/// Parse `itunes:duration`, which is either plain seconds or `[HH:]MM:SS`.
+fn parse_duration(text: &str) -> Option<u32> {
+    let parts: Vec<&str> = text.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+
+    let mut seconds: u32 = 0;
+    for part in parts {
+        let value: f64 = part.trim().parse().ok()?;
+        if !value.is_finite() || value < 0.0 || value > u32::MAX as f64 {
+            return None;
+        }
+        seconds = seconds.checked_mul(60)?.checked_add(value as u32)?;
+    }
+
+    Some(seconds)
+}
+
+fn parse_explicit(text: &str) -> Option<bool> {
+    match text.to_lowercase().as_str() {
+        "yes" | "true" | "explicit" => Some(true),
+        "no" | "false" | "clean" => Some(false),
+        _ => None,
     }
 }
 /*
@@ -150,6 +687,13 @@ impl Feed {
 }
 */
 
+/// Whether `element` has a `name` child outside of any namespace.
+fn has_rss_child(element: &roxmltree::Node, name: &str) -> bool {
+    element
+        .children()
+        .any(|child| child.tag_name().namespace().is_none() && child.tag_name().name() == name)
+}
+
 fn get_element_text<'a>(element: &'a roxmltree::Node) -> &'a str {
     match element.first_child() {
         Some(child) => child.text().unwrap_or(""),
@@ -157,64 +701,153 @@ fn get_element_text<'a>(element: &'a roxmltree::Node) -> &'a str {
     }
 }
 
-fn item_to_episode(element: &roxmltree::Node) -> Option<Episode> {
+fn item_to_episode(element: &roxmltree::Node, channel: &ITunes) -> Option<Episode> {
     let item = element.children();
 
     let mut url = String::new();
+    let mut guid = String::new();
     let mut title = String::new();
     let mut description = String::new();
+    let mut published = None;
+    let mut itunes = ITunes::default();
+    let mut podcast = Podcast::default();
 
     for item_child in item {
         if item_child.tag_name().name() == "" {
             continue;
         }
 
+        match item_child.tag_name().namespace() {
+            Some(ITUNES_NS) => {
+                itunes.parse_tag(&item_child);
+                continue;
+            }
+            Some(PODCAST_NS) => {
+                podcast.parse_tag(&item_child);
+                continue;
+            }
+            _ => (),
+        }
+
         match item_child.tag_name().name() {
             "title" => title = item_child.first_child()?.text()?.into(),
             "enclosure" => url = item_child.attribute("url")?.into(),
             "description" => description = item_child.first_child()?.text()?.into(),
+            "guid" => guid = get_element_text(&item_child).trim().into(),
+            "pubDate" => published = date::parse_date(get_element_text(&item_child)),
+            _ => (),
+        }
+    }
+
+    if url.is_empty() {
+        return None;
+    }
+
+    let mut episode = Episode::new(title, description, url);
+    if !guid.is_empty() {
+        episode.guid = guid;
+    }
+    episode.published = published;
+    itunes.apply(&mut episode, channel);
+    podcast.apply(&mut episode);
+    Some(episode)
+}
+
+fn entry_to_episode(element: &roxmltree::Node, feed: &ITunes) -> Option<Episode> {
+    let mut url = String::new();
+    let mut id = String::new();
+    let mut title = String::new();
+    let mut summary = String::new();
+    let mut content = String::new();
+    let mut published = None;
+    let mut updated = None;
+    let mut itunes = ITunes::default();
+    let mut podcast = Podcast::default();
+
+    for entry_child in element.children() {
+        match entry_child.tag_name().namespace() {
+            Some(ITUNES_NS) => {
+                itunes.parse_tag(&entry_child);
+                continue;
+            }
+            Some(PODCAST_NS) => {
+                podcast.parse_tag(&entry_child);
+                continue;
+            }
+            _ => (),
+        }
+
+        if entry_child.tag_name().namespace() != Some(ATOM_NS) {
+            continue;
+        }
+
+        match entry_child.tag_name().name() {
+            "title" => title = entry_child.first_child()?.text()?.into(),
+            "summary" => summary = get_element_text(&entry_child).into(),
+            "content" => content = get_element_text(&entry_child).into(),
+            "id" => id = get_element_text(&entry_child).trim().into(),
+            "published" => published = date::parse_rfc3339(get_element_text(&entry_child)),
+            "updated" => updated = date::parse_rfc3339(get_element_text(&entry_child)),
+            "link" if entry_child.attribute("rel") == Some("enclosure") && url.is_empty() => {
+                url = entry_child.attribute("href")?.into();
+            }
             _ => (),
         }
     }
 
-    if url == "" {
+    if url.is_empty() {
         return None;
     }
 
-    Some(Episode::new(title, description, url))
+    // atom allows both, the summary is the closest thing to an rss description.
+    let description = if summary.is_empty() { content } else { summary };
+
+    let mut episode = Episode::new(title, description, url);
+    if !id.is_empty() {
+        episode.guid = id;
+    }
+    episode.published = published.or(updated);
+    itunes.apply(&mut episode, feed);
+    podcast.apply(&mut episode);
+    Some(episode)
 }
 
-pub fn get_episodes(feed: Feed) -> Vec<Episode> {
+pub fn get_episodes(feed: Feed) -> Result<Vec<Episode>, FeedError> {
+    Ok(get_feed(feed)?.1)
+}
+
+pub fn get_feed(feed: Feed) -> Result<(Channel, Vec<Episode>), FeedError> {
     match feed {
         Feed::Path(path) => parse_path_episodes(path),
         Feed::Url(url) => parse_url_episodes(url),
     }
 }
 
-fn parse_xml_string(xml: &str) -> Vec<Episode> {
-    let doc: Result<roxmltree::Document, roxmltree::Error> = roxmltree::Document::parse(&xml);
+fn parse_xml_string(xml: &str) -> Result<(Channel, Vec<Episode>), FeedError> {
+    let doc = roxmltree::Document::parse(xml)?;
 
-    if doc.is_err() {
-        return vec![];
+    let root = doc.root_element();
+    if root.tag_name().name() == "feed" && root.tag_name().namespace() == Some(ATOM_NS) {
+        return Ok(parse_atom(&root));
     }
 
-    let doc = doc.unwrap();
-    let rss: Option<roxmltree::Node> = doc
+    let rss: roxmltree::Node = doc
         .root()
         .children()
-        .find(|item| item.tag_name().name() == "rss");
-
-    if rss.is_none() {
-        return vec![];
-    }
-
-    let rss = rss.unwrap();
+        .find(|item| item.tag_name().name() == "rss")
+        .ok_or(FeedError::NotRss)?;
 
     // validate the rss version
-    if rss.attribute("version") != Some("2.0") {
-        return vec![];
+    match rss.attribute("version") {
+        Some("2.0") => (),
+        version => {
+            return Err(FeedError::UnsupportedVersion(
+                version.unwrap_or("").to_string(),
+            ))
+        }
     }
 
+    let mut channel: Option<Channel> = None;
     let mut episodes = vec![];
 
     for child in rss.children() {
@@ -226,49 +859,170 @@ fn parse_xml_string(xml: &str) -> Vec<Episode> {
             continue;
         }
 
-        for sub_child in child.children() {
-            match sub_child.tag_name().name() {
-                // "link" => self.channel.link = get_element_text(&sub_child).into(),
-                // "title" => self.channel.title = get_element_text(&sub_child).into(),
-                // "description" => self.channel.description = get_element_text(&sub_child).into(),
-                "item" => {
-                    if let Some(episode) = item_to_episode(&sub_child) {
-                        episodes.push(episode);
-                    }
-                }
-                _ => (),
+        let itunes = parse_itunes_channel(&child);
+        // only the first channel describes the feed.
+        if channel.is_none() {
+            let first = parse_rss_channel(&child, &itunes);
+            if first.title.is_empty() {
+                return Err(FeedError::NoChannelTitle);
+            }
+            channel = Some(first);
+        }
+
+        let items = child
+            .children()
+            .filter(|sub_child| sub_child.tag_name().name() == "item");
+        for (position, item) in items.enumerate() {
+            // the only rule rss has for items.
+            if !has_rss_child(&item, "title") && !has_rss_child(&item, "description") {
+                return Err(FeedError::InvalidItem(position + 1));
+            }
+            if let Some(episode) = item_to_episode(&item, &itunes) {
+                episodes.push(episode);
             }
         }
     }
 
-    episodes
-    // panic if there is no channel
+    let channel = channel.ok_or(FeedError::NoChannel)?;
+    Ok((channel, episodes))
 }
 
-fn parse_url_episodes(url: String) -> Vec<Episode> {
-    let response: Result<minreq::Response, minreq::Error> = minreq::get(&url).send();
-    if response.is_err() {
-        return vec![];
+fn parse_atom(feed: &roxmltree::Node) -> (Channel, Vec<Episode>) {
+    let itunes = parse_itunes_channel(feed);
+
+    let episodes = feed
+        .children()
+        .filter(|child| child.tag_name().namespace() == Some(ATOM_NS))
+        .filter(|child| child.tag_name().name() == "entry")
+        .filter_map(|entry| entry_to_episode(&entry, &itunes))
+        .collect();
+
+    (parse_atom_channel(feed, &itunes), episodes)
+}
+
+/// Fetch a feed, sending `If-None-Match`/`If-Modified-Since` when the
+/// request has validators. Also returns the url the feed permanently
+/// moved to: the one its `itunes:new-feed-url` announces, once that
+/// answers with a feed, or else where the redirects led if every one of
+/// them was permanent (301 or 308).
+pub fn fetch_feed(request: &FeedRequest) -> Result<(Fetched, Option<String>), FeedError> {
+    let (fetched, moved_to) = fetch_once(request)?;
+    let url = moved_to.as_deref().unwrap_or(&request.url);
+    let new_url = match &fetched {
+        Fetched::Modified { channel, .. } => announced_url(url, &channel.new_feed_url),
+        Fetched::NotModified => None,
+    };
+    let new_url = match new_url {
+        Some(new_url) => new_url,
+        None => return Ok((fetched, moved_to)),
+    };
+
+    // the credentials stay with the server they are for.
+    let credentials = request
+        .credentials
+        .clone()
+        .filter(|_| http::origin(&new_url) == http::origin(&request.url));
+    let moved = FeedRequest {
+        credentials,
+        ..FeedRequest::new(new_url)
+    };
+    match fetch_once(&moved) {
+        Ok((fetched @ Fetched::Modified { .. }, moved_to)) => {
+            Ok((fetched, Some(moved_to.unwrap_or(moved.url))))
+        }
+        // a new url that doesn't work is no better than the old one.
+        _ => Ok((fetched, moved_to)),
     }
+}
 
-    let response = response.unwrap();
+/// The `itunes:new-feed-url` of the feed at `url`, resolved against it.
+/// Anything but another http or https url is ignored.
+fn announced_url(url: &str, new_feed_url: &str) -> Option<String> {
+    let new_url = new_feed_url.trim();
+    // a scheme without an authority, like `mailto:`.
+    let opaque =
+        new_url.split('/').next().unwrap_or_default().contains(':') && !new_url.contains("://");
+    if new_url.is_empty() || new_url.contains(char::is_whitespace) || opaque {
+        return None;
+    }
 
-    if response.status_code != 200 {
-        return vec![];
+    let new_url = http::resolve_url(url, new_url);
+    let scheme = new_url.split_once("://")?.0.to_ascii_lowercase();
+    let valid = matches!(scheme.as_str(), "http" | "https") && http::origin(&new_url).is_some();
+    Some(new_url).filter(|new_url| valid && new_url != url)
+}
+
+/// One fetch of `request.url`, see `fetch_feed`.
+fn fetch_once(request: &FeedRequest) -> Result<(Fetched, Option<String>), FeedError> {
+    let authorization = request.credentials.as_ref().map(Credentials::authorization);
+    let (response, redirects) = http::follow(
+        &request.url,
+        authorization.as_deref(),
+        &request.network,
+        |mut http| {
+            if let Some(etag) = &request.etag {
+                http = http.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &request.last_modified {
+                http = http.set("If-Modified-Since", last_modified);
+            }
+            http
+        },
+    )?;
+
+    // a temporary redirect anywhere on the way keeps the stored url.
+    let permanent = redirects
+        .iter()
+        .all(|(status, _)| matches!(status, 301 | 308));
+    let moved_to = redirects
+        .last()
+        .filter(|_| permanent)
+        .map(|(_, url)| url.clone());
+
+    match response.status() {
+        200 => (),
+        304 => return Ok((Fetched::NotModified, moved_to)),
+        status @ (401 | 403) => return Err(FeedError::Unauthorized(status)),
+        status => return Err(FeedError::HttpStatus(status)),
     }
 
-    match response.as_str() {
-        Ok(content) => parse_xml_string(content),
-        Err(_) => vec![],
+    let etag = response.header("etag").map(String::from);
+    let last_modified = response.header("last-modified").map(String::from);
+    let mut body = vec![];
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| FeedError::Network(e.to_string()))?;
+
+    // feeds lying about their encoding are common enough, keep what we can.
+    let content = String::from_utf8_lossy(&body);
+    let (channel, episodes) = parse_xml_string(&content)?;
+
+    let fetched = Fetched::Modified {
+        channel: Box::new(channel),
+        episodes,
+        etag,
+        last_modified,
+    };
+    Ok((fetched, moved_to))
+}
+
+fn parse_url_episodes(url: String) -> Result<(Channel, Vec<Episode>), FeedError> {
+    match fetch_feed(&FeedRequest::new(url))?.0 {
+        Fetched::Modified {
+            channel, episodes, ..
+        } => Ok((*channel, episodes)),
+        // without validators there is nothing to be unmodified from.
+        Fetched::NotModified => Err(FeedError::HttpStatus(304)),
     }
 }
 
-fn parse_path_episodes(path: PathBuf) -> Vec<Episode> {
-    let file = std::fs::File::open(path).expect("File not found");
+fn parse_path_episodes(path: PathBuf) -> Result<(Channel, Vec<Episode>), FeedError> {
+    let file = std::fs::File::open(path)?;
 
     let mut reader = std::io::BufReader::new(file);
     let mut xml = String::new();
-    reader.read_to_string(&mut xml).expect("couldn't read file");
+    reader.read_to_string(&mut xml)?;
 
     parse_xml_string(&xml)
 }
@@ -283,7 +1037,7 @@ fn can_parse_xml_files() {
     let root_path = env::current_dir().expect("something is wrong with finding current dir.");
     let path = root_path.join("feeds/valid_basic.xml");
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
+    let episodes = get_episodes(feed).unwrap();
     assert_eq!(episodes.len(), 3);
 }
 
@@ -292,7 +1046,7 @@ fn test_feed_validation_complete() {
     let root_path = env::current_dir().expect("something is wrong with finding current dir.");
     let path = root_path.join("feeds/valid_basic.xml");
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
+    let episodes = get_episodes(feed).unwrap();
     assert_eq!(episodes.len(), 3);
 }
 
@@ -301,7 +1055,7 @@ fn test_feed_validation_valid_mixed_enclosure() {
     let root_path = env::current_dir().expect("something is wrong with finding current dir.");
     let path = root_path.join("feeds/valid_mixed_enclosures.xml");
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
+    let episodes = get_episodes(feed).unwrap();
     assert_eq!(episodes.len(), 2);
 }
 
@@ -310,8 +1064,7 @@ fn test_feed_validations_is_rss() {
     let root_path = env::current_dir().expect("something is wrong with finding current dir.");
     let path = root_path.join("feeds/broken_is_rss.xml");
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(get_episodes(feed), Err(FeedError::NotRss)));
 }
 
 #[test]
@@ -320,8 +1073,10 @@ fn test_feed_validations_is_v2() {
     let path = root_path.join("feeds/broken_is_v2.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(
+        get_episodes(feed),
+        Err(FeedError::UnsupportedVersion(version)) if version == "1.0"
+    ));
 }
 
 #[test]
@@ -330,8 +1085,7 @@ fn test_feed_validations_rss_empty() {
     let path = root_path.join("feeds/broken_rss_empty.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(get_episodes(feed), Err(FeedError::NoChannel)));
 }
 
 #[test]
@@ -340,8 +1094,10 @@ fn test_feed_validations_has_channel() {
     let path = root_path.join("feeds/broken_has_channel.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(
+        get_episodes(feed),
+        Err(FeedError::UnsupportedVersion(_))
+    ));
 }
 
 #[test]
@@ -350,8 +1106,7 @@ fn test_feed_validations_channel_children() {
     let path = root_path.join("feeds/broken_channel_children.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(get_episodes(feed), Err(FeedError::NoChannelTitle)));
 }
 
 #[test]
@@ -360,8 +1115,7 @@ fn test_feed_validations_channel_empty() {
     let path = root_path.join("feeds/broken_channel_empty.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(get_episodes(feed), Err(FeedError::NoChannelTitle)));
 }
 
 #[test]
@@ -370,7 +1124,7 @@ fn test_feed_validations_two_channels() {
     let path = root_path.join("feeds/valid_two_channels.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
+    let episodes = get_episodes(feed).unwrap();
     assert_eq!(episodes.len(), 3);
 }
 
@@ -380,15 +1134,560 @@ fn test_feed_validations_item_title() {
     let path = root_path.join("feeds/broken_item_title.xml");
 
     let feed = Feed::Path(path.to_path_buf());
-    let episodes = get_episodes(feed);
-    assert_eq!(episodes.len(), 0);
+    assert!(matches!(get_episodes(feed), Err(FeedError::InvalidItem(2))));
+}
+
+#[test]
+fn test_feed_atom_valid() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_atom.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let episodes = get_episodes(feed).unwrap();
+    assert_eq!(
+        episodes,
+        vec![
+            Episode {
+                guid: "urn:uuid:1225c695-cfb8-4ebb-aaaa-80da344efa6a".into(),
+                published: Some(1071340202),
+                ..Episode::new(
+                    "myfeed entry1 title",
+                    "myfeed entry1 summary",
+                    "http://example.com/myfeed_entry1_title.mp3"
+                )
+            },
+            Episode::new(
+                "myfeed entry2 title",
+                "myfeed entry2 content",
+                "http://example.com/myfeed_entry2_title.mp3"
+            ),
+            Episode::new("", "", "http://example.com/myfeed_entry3_title.mp3"),
+        ]
+    );
+}
+
+#[test]
+fn test_feed_atom_mixed_links() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_atom_mixed_links.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let episodes = get_episodes(feed).unwrap();
+    assert_eq!(episodes.len(), 1);
+    assert_eq!(
+        episodes[0].url,
+        "http://example.com/myfeed_entry2_title.mp3"
+    );
+}
+
+#[test]
+fn test_feed_validations_atom_namespace() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/broken_atom_namespace.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    assert!(matches!(get_episodes(feed), Err(FeedError::NotRss)));
+}
+
+#[test]
+fn test_feed_itunes_fields() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_itunes.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let episodes = get_episodes(feed).unwrap();
+    assert_eq!(episodes.len(), 3);
+
+    assert_eq!(
+        episodes[0],
+        Episode {
+            guid: "http://example.com/myfeed_item1_title.mp3".into(),
+            url: "http://example.com/myfeed_item1_title.mp3".into(),
+            title: "myfeed item1 title".into(),
+            description: "myfeed item1 description".into(),
+            duration: Some(3723),
+            episode: Some(5),
+            season: Some(2),
+            episode_type: EpisodeType::Full,
+            explicit: false,
+            image: "http://example.com/item1.jpg".into(),
+            ..Default::default()
+        }
+    );
+
+    // summary fills in a missing description, image and explicit come from the channel.
+    assert_eq!(episodes[1].description, "myfeed item2 summary");
+    assert_eq!(episodes[1].duration, Some(754));
+    assert_eq!(episodes[1].episode_type, EpisodeType::Trailer);
+    assert!(episodes[1].explicit);
+    assert_eq!(episodes[1].image, "http://example.com/channel.jpg");
+
+    assert_eq!(episodes[2].duration, Some(1800));
+    assert_eq!(episodes[2].episode, None);
+    assert_eq!(episodes[2].episode_type, EpisodeType::Bonus);
+}
+
+#[test]
+fn test_feed_itunes_duration() {
+    assert_eq!(parse_duration("3723"), Some(3723));
+    assert_eq!(parse_duration("1:02:03"), Some(3723));
+    assert_eq!(parse_duration("62:03"), Some(3723));
+    assert_eq!(parse_duration("3723.8"), Some(3723));
+    assert_eq!(parse_duration(""), None);
+    assert_eq!(parse_duration("one hour"), None);
+    assert_eq!(parse_duration("1:2:3:4"), None);
+    // feeds are untrusted, absurd values are dropped instead of wrapping.
+    assert_eq!(parse_duration("99999999:00"), None);
+    assert_eq!(parse_duration("99999999999"), None);
+    assert_eq!(parse_duration("inf"), None);
+    assert_eq!(parse_duration("NaN"), None);
+}
+
+#[test]
+fn test_feed_podcast_namespace() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_podcast_namespace.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let (channel, episodes) = get_feed(feed).unwrap();
+
+    assert_eq!(channel.guid, "917393e3-1b1e-5cef-ace4-edaa54e1f810");
+    assert_eq!(
+        channel.funding,
+        vec![Funding {
+            url: "https://example.com/donate".into(),
+            message: "Support the show!".into(),
+        }]
+    );
+    assert_eq!(channel.persons.len(), 1);
+    assert_eq!(channel.persons[0].name, "Jane Host");
+    assert_eq!(channel.persons[0].role, "host");
+    assert_eq!(channel.persons[0].group, "cast");
+
+    assert_eq!(episodes.len(), 2);
+
+    let episode = &episodes[0];
+    assert_eq!(episode.season, Some(3));
+    assert_eq!(episode.episode, Some(12));
+    assert_eq!(
+        episode.chapters,
+        Some(Chapters {
+            url: "https://example.com/ep12/chapters.json".into(),
+            kind: "application/json+chapters".into(),
+        })
+    );
+    assert_eq!(
+        episode.transcripts,
+        vec![
+            Transcript {
+                url: "https://example.com/ep12/transcript.srt".into(),
+                kind: "application/srt".into(),
+                language: "en".into(),
+                rel: "captions".into(),
+            },
+            Transcript {
+                url: "https://example.com/ep12/transcript.html".into(),
+                kind: "text/html".into(),
+                language: "".into(),
+                rel: "".into(),
+            },
+        ]
+    );
+    assert_eq!(
+        episode.persons,
+        vec![Person {
+            name: "John Guest".into(),
+            role: "guest".into(),
+            group: "cast".into(),
+            image: "https://example.com/john.jpg".into(),
+            href: "https://example.com/john".into(),
+        }]
+    );
+    assert_eq!(
+        episode.soundbites,
+        vec![Soundbite {
+            start: 73.0,
+            duration: 60.5,
+            title: "The best part".into(),
+        }]
+    );
+
+    // itunes numbers win over the podcast ones, a soundbite needs both times.
+    let episode = &episodes[1];
+    assert_eq!(episode.season, Some(1));
+    assert_eq!(episode.episode, Some(13));
+    assert_eq!(episode.chapters, None);
+    assert!(episode.persons.is_empty());
+    assert!(episode.soundbites.is_empty());
+}
+
+#[test]
+fn test_feed_rss_channel() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_channel.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let (channel, episodes) = get_feed(feed).unwrap();
+    assert_eq!(episodes.len(), 1);
+    assert_eq!(
+        channel,
+        Channel {
+            title: "myfeed title".into(),
+            link: "http://example.com/".into(),
+            description: "myfeed description".into(),
+            image: "http://example.com/itunes.jpg".into(),
+            language: "en-us".into(),
+            author: "myfeed itunes author".into(),
+            ..Default::default()
+        }
+    );
+
+    // without itunes tags the rss ones are used.
+    let path = root_path.join("feeds/valid_complete.xml");
+    let (channel, _) = get_feed(Feed::Path(path)).unwrap();
+    assert_eq!(channel.title, "myfeed title");
+    assert_eq!(channel.link, "myfeed link");
+    assert_eq!(channel.description, "myfeed description");
+    assert_eq!(channel.image, "");
+}
+
+#[test]
+fn test_feed_atom_channel() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_atom.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let (channel, _) = get_feed(feed).unwrap();
+    assert_eq!(
+        channel,
+        Channel {
+            title: "myfeed title".into(),
+            link: "http://example.com/".into(),
+            description: "myfeed subtitle".into(),
+            image: "http://example.com/logo.png".into(),
+            language: "en".into(),
+            author: "myfeed author".into(),
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_feed_item_guid() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_guid.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let episodes = get_episodes(feed).unwrap();
+    assert_eq!(episodes.len(), 3);
+    assert_eq!(episodes[0].guid, "myfeed-item1-guid");
+    assert_eq!(episodes[1].guid, "http://example.com/item2");
+    // no guid, the enclosure identifies the item.
+    assert_eq!(
+        episodes[2].guid,
+        "http://example.com/myfeed_item3_title.mp3"
+    );
+}
+
+#[test]
+fn test_feed_item_pubdate() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_pubdate.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    let episodes = get_episodes(feed).unwrap();
+    assert_eq!(episodes.len(), 4);
+    assert_eq!(episodes[0].published, Some(1055217600));
+    // malformed but still readable.
+    assert_eq!(episodes[1].published, Some(1055217600));
+    assert_eq!(episodes[2].published, None);
+    assert_eq!(episodes[3].published, None);
+}
+
+#[test]
+fn test_feed_validations_no_channel() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/broken_no_channel.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    assert!(matches!(get_episodes(feed), Err(FeedError::NoChannel)));
+}
+
+#[test]
+fn test_feed_validations_no_version() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/broken_no_version.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    assert!(matches!(
+        get_episodes(feed),
+        Err(FeedError::UnsupportedVersion(version)) if version.is_empty()
+    ));
+}
+
+#[test]
+fn test_feed_validations_invalid_xml() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/broken_invalid_xml.xml");
+
+    let feed = Feed::Path(path.to_path_buf());
+    match get_episodes(feed) {
+        Err(FeedError::InvalidXml { row, .. }) => assert_eq!(row, 8),
+        other => panic!("expected invalid xml, got {:?}", other),
+    }
 }
 
 #[test]
-#[should_panic]
 fn test_feed_load_error() {
     let path = Path::new("notfound");
     let feed = Feed::Path(path.to_path_buf());
-    let _episodes = get_episodes(feed);
+    assert!(matches!(get_episodes(feed), Err(FeedError::Io(_))));
 }
 
+#[test]
+fn test_fetch_feed_conditional_get() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (url, requests) = http::serve(vec![
+        http::http_response(
+            "200 OK",
+            &[
+                "ETag: \"v1\"",
+                "Last-Modified: Tue, 10 Jun 2003 04:00:00 GMT",
+            ],
+            &body,
+        ),
+        http::http_response("304 Not Modified", &[], ""),
+    ]);
+
+    let mut request = FeedRequest::new(url);
+    match fetch_feed(&request).unwrap().0 {
+        Fetched::Modified {
+            episodes,
+            etag,
+            last_modified,
+            ..
+        } => {
+            assert_eq!(episodes.len(), 3);
+            request.etag = etag;
+            request.last_modified = last_modified;
+        }
+        Fetched::NotModified => panic!("the first fetch has nothing to compare to"),
+    }
+    let first = requests.recv().unwrap().to_lowercase();
+    assert!(!first.contains("if-none-match"));
+
+    assert_eq!(request.etag.as_deref(), Some("\"v1\""));
+    assert!(matches!(
+        fetch_feed(&request).unwrap(),
+        (Fetched::NotModified, None)
+    ));
+    let second = requests.recv().unwrap().to_lowercase();
+    assert!(second.contains("if-none-match: \"v1\""));
+    assert!(second.contains("if-modified-since: tue, 10 jun 2003 04:00:00 gmt"));
+}
+
+#[test]
+fn test_fetch_feed_http_status() {
+    let (url, _requests) = http::serve(vec![http::http_response("404 Not Found", &[], "")]);
+    assert!(matches!(
+        fetch_feed(&FeedRequest::new(url)),
+        Err(FeedError::HttpStatus(404))
+    ));
+}
+
+#[test]
+fn test_fetch_feed_permanent_redirect() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (url, requests) = http::serve(vec![
+        http::http_response("308 Permanent Redirect", &["Location: /moved.xml"], ""),
+        http::http_response("200 OK", &[], &body),
+    ]);
+
+    let (fetched, moved_to) = fetch_feed(&FeedRequest::new(url.clone())).unwrap();
+    assert!(matches!(fetched, Fetched::Modified { .. }));
+    assert_eq!(moved_to, Some(url.replace("/feed.xml", "/moved.xml")));
+    assert!(requests.recv().unwrap().starts_with("GET /feed.xml "));
+    assert!(requests.recv().unwrap().starts_with("GET /moved.xml "));
+}
+
+#[test]
+fn test_fetch_feed_moved_permanently() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (url, requests) = http::serve(vec![
+        http::http_response("301 Moved Permanently", &["Location: /moved.xml"], ""),
+        http::http_response("200 OK", &[], &body),
+        http::http_response("301 Moved Permanently", &["Location: /moved.xml"], ""),
+        http::http_response("302 Found", &["Location: /elsewhere.xml"], ""),
+        http::http_response("200 OK", &[], &body),
+    ]);
+
+    let (_, moved_to) = fetch_feed(&FeedRequest::new(url.clone())).unwrap();
+    assert_eq!(moved_to, Some(url.replace("/feed.xml", "/moved.xml")));
+    assert!(requests.recv().unwrap().starts_with("GET /feed.xml "));
+    assert!(requests.recv().unwrap().starts_with("GET /moved.xml "));
+
+    // a temporary redirect on the way keeps the stored url.
+    let (_, moved_to) = fetch_feed(&FeedRequest::new(url)).unwrap();
+    assert_eq!(moved_to, None);
+}
+
+#[test]
+fn test_fetch_feed_new_feed_url() {
+    let body = std::fs::read_to_string("feeds/valid_itunes.xml")
+        .unwrap()
+        .replace("http://example.com/new-feed.xml", "new-feed.xml");
+    let (url, requests) = http::serve(vec![
+        http::http_response("200 OK", &[], &body),
+        http::http_response("200 OK", &[], &body),
+        http::http_response("200 OK", &[], &body),
+        http::http_response("404 Not Found", &[], ""),
+    ]);
+
+    let (_, moved_to) = fetch_feed(&FeedRequest::new(url.clone())).unwrap();
+    assert_eq!(moved_to, Some(url.replace("/feed.xml", "/new-feed.xml")));
+    assert!(requests.recv().unwrap().starts_with("GET /feed.xml "));
+    assert!(requests.recv().unwrap().starts_with("GET /new-feed.xml "));
+
+    // the new url doesn't work yet, the feed stays where it is.
+    let (fetched, moved_to) = fetch_feed(&FeedRequest::new(url)).unwrap();
+    assert!(matches!(fetched, Fetched::Modified { .. }));
+    assert_eq!(moved_to, None);
+}
+
+#[test]
+fn test_announced_url() {
+    let url = "https://example.com/feeds/show.xml";
+    assert_eq!(
+        announced_url(url, " https://new.example/show.xml "),
+        Some("https://new.example/show.xml".into())
+    );
+    assert_eq!(
+        announced_url(url, "new.xml"),
+        Some("https://example.com/feeds/new.xml".into())
+    );
+    assert_eq!(announced_url(url, ""), None);
+    assert_eq!(announced_url(url, url), None);
+    assert_eq!(announced_url(url, "ftp://example.com/show.xml"), None);
+    assert_eq!(announced_url(url, "mailto:host@example.com"), None);
+    assert_eq!(announced_url(url, "not a url"), None);
+
+    let path = env::current_dir()
+        .unwrap()
+        .join("feeds/valid_bogus_new_feed_url.xml");
+    let (channel, _) = get_feed(Feed::Path(path)).unwrap();
+    assert_eq!(announced_url(url, &channel.new_feed_url), None);
+}
+
+#[test]
+fn test_feed_itunes_new_feed_url() {
+    let root_path = env::current_dir().expect("something is wrong with finding current dir.");
+    let path = root_path.join("feeds/valid_itunes.xml");
+
+    let (channel, _) = get_feed(Feed::Path(path.to_path_buf())).unwrap();
+    assert_eq!(channel.new_feed_url, "http://example.com/new-feed.xml");
+}
+
+#[test]
+fn test_fetch_feed_basic_auth() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (url, requests) = http::serve(vec![
+        http::http_response("401 Unauthorized", &[], ""),
+        http::http_response("200 OK", &[], &body),
+    ]);
+
+    let mut request = FeedRequest::new(url);
+    assert!(matches!(
+        fetch_feed(&request),
+        Err(FeedError::Unauthorized(401))
+    ));
+    assert!(!requests.recv().unwrap().contains("Authorization"));
+
+    request.credentials = Some(Credentials {
+        username: "user".into(),
+        password: "secret".into(),
+    });
+    assert!(fetch_feed(&request).is_ok());
+    // base64 of "user:secret"
+    assert!(requests
+        .recv()
+        .unwrap()
+        .contains("Authorization: Basic dXNlcjpzZWNyZXQ="));
+}
+
+#[test]
+fn test_fetch_feed_redirect_keeps_credentials_home() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (other, other_requests) = http::serve(vec![http::http_response("200 OK", &[], &body)]);
+    // another host for the same test server.
+    let location = format!("Location: {}", other.replace("127.0.0.1", "localhost"));
+    let (url, requests) = http::serve(vec![
+        http::http_response("302 Found", &["Location: /same.xml"], ""),
+        http::http_response("308 Permanent Redirect", &[&location], ""),
+    ]);
+
+    let mut request = FeedRequest::new(url);
+    request.credentials = Some(Credentials {
+        username: "user".into(),
+        password: "secret".into(),
+    });
+    assert!(fetch_feed(&request).is_ok());
+    assert!(requests.recv().unwrap().contains("Authorization"));
+    assert!(requests.recv().unwrap().contains("Authorization"));
+    assert!(!other_requests.recv().unwrap().contains("Authorization"));
+}
+
+#[test]
+fn test_split_credentials() {
+    assert_eq!(
+        split_credentials("https://user:p@ss@example.com/feed?x=1"),
+        (
+            "https://example.com/feed?x=1".to_string(),
+            Some(Credentials {
+                username: "user".into(),
+                password: "p@ss".into(),
+            })
+        )
+    );
+    assert_eq!(
+        split_credentials("https://example.com/feed?mail=a@b.com"),
+        ("https://example.com/feed?mail=a@b.com".to_string(), None)
+    );
+    assert_eq!(
+        redact_url("https://user:pw@example.com/feed?token=secret"),
+        "https://example.com/feed?…"
+    );
+}
+
+#[test]
+fn test_fetch_feed_retries_temporary_errors() {
+    let body = std::fs::read_to_string("feeds/valid_guid.xml").unwrap();
+    let (url, requests) = http::serve(vec![
+        http::http_response("503 Service Unavailable", &[], ""),
+        http::http_response("200 OK", &[], &body),
+    ]);
+
+    let mut request = FeedRequest::new(url);
+    request.network.retries = 1;
+    request.network.retry_delay = 10;
+    request.network.user_agent = "minicast-test".into();
+    assert!(fetch_feed(&request).is_ok());
+
+    assert!(requests
+        .recv()
+        .unwrap()
+        .contains("User-Agent: minicast-test"));
+    assert!(requests.recv().is_ok());
+}
+
+#[test]
+fn test_fetch_feed_timeout() {
+    // accepts the connection but never answers.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/feed.xml", listener.local_addr().unwrap());
+
+    let mut request = FeedRequest::new(url);
+    request.network.read_timeout = 1;
+    request.network.retries = 0;
+    assert!(matches!(fetch_feed(&request), Err(FeedError::Network(_))));
+    drop(listener);
+}
@@ -0,0 +1,61 @@
+use crate::controller::ControllerMessage;
+use crate::feed::{self, FeedRequest};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// How many feeds are fetched at the same time.
+const WORKERS: usize = 4;
+
+struct Job {
+    feed_id: u32,
+    request: FeedRequest,
+}
+
+/// A pool of threads fetching feeds off the ui thread, results are sent
+/// back to the controller as `Fetch*` messages.
+pub struct Fetcher {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Fetcher {
+    pub fn new(controller_tx: mpsc::Sender<ControllerMessage>) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..WORKERS {
+            let rx = Arc::clone(&rx);
+            let controller_tx = controller_tx.clone();
+            thread::spawn(move || loop {
+                // the lock is released as soon as a job is taken.
+                let job = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+
+                if controller_tx
+                    .send(ControllerMessage::FetchStarted(job.feed_id))
+                    .is_err()
+                {
+                    break;
+                }
+
+                let message = match feed::fetch_feed(&job.request) {
+                    Ok((fetched, moved_to)) => {
+                        ControllerMessage::FetchFinished(job.feed_id, fetched, moved_to)
+                    }
+                    Err(e) => ControllerMessage::FetchFailed(job.feed_id, e.to_string()),
+                };
+
+                if controller_tx.send(message).is_err() {
+                    break;
+                }
+            });
+        }
+
+        Fetcher { jobs }
+    }
+
+    pub fn fetch(&self, feed_id: u32, request: FeedRequest) {
+        self.jobs.send(Job { feed_id, request }).unwrap();
+    }
+}
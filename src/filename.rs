@@ -0,0 +1,142 @@
+use std::path::PathBuf;
+
+/// The fields a filename template can use, as `{name}`.
+const FIELDS: &[&str] = &["feed", "title", "date", "ext", "id"];
+
+/// Longest a single file or directory name gets, in characters.
+const MAX_NAME: usize = 120;
+
+/// Values for the fields of a template.
+pub struct Fields<'a> {
+    pub feed: &'a str,
+    pub title: &'a str,
+    /// publication date as a unix timestamp.
+    pub date: Option<i64>,
+    pub ext: &'a str,
+    pub id: u32,
+}
+
+/// Make sure `template` only uses known fields and names a file.
+pub fn check(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {}", template))?;
+        let field = &rest[start + 1..start + end];
+        if !FIELDS.contains(&field) {
+            return Err(format!("unknown field {{{}}}", field));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if template.ends_with('/') || template.trim().is_empty() {
+        return Err("the template doesn't name a file".into());
+    }
+    Ok(())
+}
+
+/// The relative path `template` makes for `fields`. Every `/` in the
+/// template starts a directory, the values never do.
+pub fn render(template: &str, fields: &Fields) -> PathBuf {
+    let date = fields
+        .date
+        .map(crate::date::format_date)
+        .unwrap_or_else(|| "undated".into());
+    let id = fields.id.to_string();
+
+    let mut path = PathBuf::new();
+    for part in template.split('/') {
+        let mut name = part.to_string();
+        for (field, value) in [
+            ("{feed}", fields.feed),
+            ("{title}", fields.title),
+            ("{date}", date.as_str()),
+            ("{ext}", fields.ext),
+            ("{id}", id.as_str()),
+        ] {
+            name = name.replace(field, &sanitize(value));
+        }
+        let name = trim_name(&name);
+        if !name.is_empty() && name != "." && name != ".." {
+            path.push(name);
+        }
+    }
+    if path.as_os_str().is_empty() {
+        path.push(format!("{}.{}", id, fields.ext));
+    }
+    path
+}
+
+/// Characters that aren't allowed or are awkward in file names on the
+/// usual file systems, and control characters, become `_`.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Cut overly long names, keeping the extension, and drop the leading and
+/// trailing spaces and dots windows chokes on.
+fn trim_name(name: &str) -> String {
+    let name = name.trim_matches(|c: char| c == ' ' || c == '.');
+    if name.chars().count() <= MAX_NAME {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if extension.len() <= 4 => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    match extension {
+        Some(extension) => {
+            let stem: String = stem.chars().take(MAX_NAME - extension.len() - 1).collect();
+            format!("{}.{}", stem, extension)
+        }
+        None => stem.chars().take(MAX_NAME).collect(),
+    }
+}
+
+#[cfg(test)]
+fn test_fields(title: &str) -> Fields<'_> {
+    Fields {
+        feed: "The Show",
+        title,
+        date: Some(1_600_000_000),
+        ext: "mp3",
+        id: 7,
+    }
+}
+
+#[test]
+fn test_check_template() {
+    assert!(check("{feed}/{date} - {title}.{ext}").is_ok());
+    assert!(check("{id}.mp3").is_ok());
+    assert!(check("{feed}/{name}.{ext}").is_err());
+    assert!(check("{feed}/{title").is_err());
+    assert!(check("{feed}/").is_err());
+}
+
+#[test]
+fn test_render_template() {
+    let template = "{feed}/{date} - {title}.{ext}";
+    assert_eq!(
+        render(template, &test_fields("Episode 12: Why?")),
+        PathBuf::from("The Show/2020-09-13 - Episode 12_ Why_.mp3")
+    );
+    // values can't escape the download directory.
+    assert_eq!(
+        render(template, &test_fields("../../etc/passwd")),
+        PathBuf::from("The Show/2020-09-13 - .._.._etc_passwd.mp3")
+    );
+    assert_eq!(render("{title}", &test_fields("")), PathBuf::from("7.mp3"));
+
+    let long = "a".repeat(300);
+    let path = render("{title}.{ext}", &test_fields(&long));
+    let name = path.to_str().unwrap();
+    assert_eq!(name.len(), MAX_NAME);
+    assert!(name.ends_with("a.mp3"));
+}
@@ -0,0 +1,374 @@
+//! Every http request goes through here, so the network settings apply
+//! the same way to feeds and downloads.
+
+use crate::settings::NetworkSettings;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// The longest wait between two retries, however many there are.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many redirects are followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// Why a request got no response.
+#[derive(Debug)]
+pub enum Error {
+    Transport(Box<ureq::Transport>),
+    TooManyRedirects,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "{}", e),
+            Error::TooManyRedirects => write!(f, "too many redirects"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ureq::Transport> for Error {
+    fn from(e: ureq::Transport) -> Self {
+        Error::Transport(Box::new(e))
+    }
+}
+
+/// A GET request with the user agent, proxy and timeouts of `network`.
+/// Redirects come back as they are, `follow` is what follows them.
+pub fn get(url: &str, network: &NetworkSettings) -> Result<ureq::Request, Error> {
+    let mut agent = ureq::AgentBuilder::new()
+        .user_agent(&network.user_agent)
+        .redirects(0)
+        .timeout_connect(Duration::from_secs(network.connect_timeout));
+
+    if network.read_timeout > 0 {
+        agent = agent.timeout_read(Duration::from_secs(network.read_timeout));
+    }
+
+    if let Some(proxy) = &network.proxy {
+        agent = agent.proxy(parse_proxy(proxy)?);
+    }
+
+    Ok(agent.build().get(url))
+}
+
+/// Send the request `build` makes, again after a network error or a
+/// temporary server error, waiting twice as long before each retry.
+/// Error statuses are responses like any other.
+pub fn send<F>(build: F, network: &NetworkSettings) -> Result<ureq::Response, Error>
+where
+    F: Fn() -> Result<ureq::Request, Error>,
+{
+    let mut attempt = 0;
+    loop {
+        let result = match build()?.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+            Err(ureq::Error::Transport(e)) => Err(e),
+        };
+
+        let retry = match &result {
+            Ok(response) => is_temporary(response.status()),
+            Err(e) => is_network_error(e),
+        };
+        if !retry || attempt >= network.retries {
+            return result.map_err(Error::from);
+        }
+
+        thread::sleep(retry_delay(network, attempt));
+        attempt += 1;
+    }
+}
+
+/// Send the request `build` makes from `get(url)`, then from the url of
+/// every redirect. Returns the last response and the redirects followed,
+/// as the status and the url redirected to.
+///
+/// `authorization` is for the server at `url`: it is only sent while the
+/// redirects stay on its scheme, host and port.
+pub fn follow<F>(
+    url: &str,
+    authorization: Option<&str>,
+    network: &NetworkSettings,
+    build: F,
+) -> Result<(ureq::Response, Vec<(u16, String)>), Error>
+where
+    F: Fn(ureq::Request) -> ureq::Request,
+{
+    let home = origin(url);
+    let mut authorization = authorization.filter(|_| home.is_some());
+    let mut url = url.to_string();
+    let mut redirects = vec![];
+    loop {
+        // once a redirect leaves the server, the credentials stay behind.
+        if origin(&url) != home {
+            authorization = None;
+        }
+        let response = send(
+            || {
+                let http = build(get(&url, network)?);
+                Ok(match authorization {
+                    Some(authorization) => http.set("Authorization", authorization),
+                    None => http,
+                })
+            },
+            network,
+        )?;
+
+        let location = match (response.status(), response.header("location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => resolve_url(&url, location),
+            _ => return Ok((response, redirects)),
+        };
+        if redirects.len() >= MAX_REDIRECTS {
+            return Err(Error::TooManyRedirects);
+        }
+        redirects.push((response.status(), location.clone()));
+        url = location;
+    }
+}
+
+/// Scheme, host and port of `url`, the port filled in from the scheme.
+pub fn origin(url: &str) -> Option<(String, String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let scheme = scheme.to_ascii_lowercase();
+    let authority = rest.split(&['/', '?', '#'][..]).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (host, port) = match host.rsplit_once(':') {
+        // the colons of an IPv6 address without a port.
+        Some((host, port)) if !port.ends_with(']') => (host, port.parse().ok()?),
+        _ => match scheme.as_str() {
+            "http" => (host, 80),
+            "https" => (host, 443),
+            _ => return None,
+        },
+    };
+    Some((scheme, host.to_ascii_lowercase(), port))
+}
+
+/// Resolve the `Location` of a redirect against the url that sent it.
+pub fn resolve_url(base: &str, location: &str) -> String {
+    if has_scheme(location) {
+        return location.into();
+    }
+
+    let scheme_end = base.find("://").map(|idx| idx + 3).unwrap_or(0);
+    let host_end = base[scheme_end..]
+        .find('/')
+        .map(|idx| idx + scheme_end)
+        .unwrap_or(base.len());
+
+    if let Some(path) = location.strip_prefix("//") {
+        format!("{}{}", &base[..scheme_end], path)
+    } else if location.starts_with('/') {
+        format!("{}{}", &base[..host_end], location)
+    } else {
+        // relative to the directory of the current path.
+        let dir = match base[host_end..].rfind('/') {
+            Some(idx) => base[..host_end + idx + 1].to_string(),
+            None => format!("{}/", base),
+        };
+        format!("{}{}", dir, location)
+    }
+}
+
+/// Whether `url` starts with a scheme, in any case, like `HTTPS://`.
+fn has_scheme(url: &str) -> bool {
+    match url.split_once("://") {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        None => false,
+    }
+}
+
+/// `retry_delay` doubled for every earlier retry, up to `MAX_RETRY_DELAY`.
+pub fn retry_delay(network: &NetworkSettings, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    Duration::from_millis(network.retry_delay.saturating_mul(factor)).min(MAX_RETRY_DELAY)
+}
+
+/// `host:port`, `user:password@host:port` or the same behind `http://`,
+/// `socks4://`, `socks4a://` or `socks5://`.
+fn parse_proxy(proxy: &str) -> Result<ureq::Proxy, Error> {
+    ureq::Proxy::new(proxy).map_err(|e| match e {
+        ureq::Error::Transport(e) => Error::from(e),
+        ureq::Error::Status(..) => unreachable!("parsing a proxy sends nothing"),
+    })
+}
+
+fn is_temporary(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+fn is_network_error(e: &ureq::Transport) -> bool {
+    matches!(
+        e.kind(),
+        ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+    )
+}
+
+/// Serve `responses` one connection at a time on a local port, sending
+/// the request heads back through the returned channel.
+#[cfg(test)]
+pub fn serve(responses: Vec<String>) -> (String, std::sync::mpsc::Receiver<String>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/feed.xml", listener.local_addr().unwrap());
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            tx.send(head).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    (url, rx)
+}
+
+#[cfg(test)]
+pub fn http_response(status: &str, headers: &[&str], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response
+}
+
+#[test]
+fn test_retry_delay() {
+    let network = NetworkSettings::default();
+    assert_eq!(retry_delay(&network, 0), Duration::from_secs(1));
+    assert_eq!(retry_delay(&network, 2), Duration::from_secs(4));
+    assert_eq!(retry_delay(&network, 40), MAX_RETRY_DELAY);
+    assert_eq!(retry_delay(&network, 200), MAX_RETRY_DELAY);
+}
+
+#[test]
+fn test_resolve_url() {
+    let base = "https://example.com/feeds/show.xml";
+    assert_eq!(
+        resolve_url(base, "http://other.com/feed"),
+        "http://other.com/feed"
+    );
+    assert_eq!(
+        resolve_url(base, "HTTPS://Other.com/feed"),
+        "HTTPS://Other.com/feed"
+    );
+    assert_eq!(
+        resolve_url(base, "ftp://other.com/feed"),
+        "ftp://other.com/feed"
+    );
+    assert_eq!(resolve_url(base, "//cdn.com/feed"), "https://cdn.com/feed");
+    assert_eq!(resolve_url(base, "/new.xml"), "https://example.com/new.xml");
+    assert_eq!(
+        resolve_url(base, "new.xml"),
+        "https://example.com/feeds/new.xml"
+    );
+    assert_eq!(
+        resolve_url("https://example.com", "new.xml"),
+        "https://example.com/new.xml"
+    );
+}
+
+#[test]
+fn test_origin() {
+    let origin =
+        |url| origin(url).map(|(scheme, host, port)| format!("{} {} {}", scheme, host, port));
+    assert_eq!(
+        origin("HTTPS://Example.com/feed?x=1"),
+        Some("https example.com 443".into())
+    );
+    assert_eq!(
+        origin("http://user:pw@example.com:8080"),
+        Some("http example.com 8080".into())
+    );
+    assert_eq!(origin("http://[::1]/feed"), Some("http [::1] 80".into()));
+    assert_eq!(origin("http://[::1]:81/feed"), Some("http [::1] 81".into()));
+    assert_eq!(origin("http://example.com:port/"), None);
+}
+
+#[test]
+fn test_parse_proxy() {
+    assert!(parse_proxy("localhost:3128").is_ok());
+    assert!(parse_proxy("http://user:pw@localhost:3128/").is_ok());
+    assert!(parse_proxy("socks5://localhost:1080").is_ok());
+    assert!(parse_proxy("ftp://localhost").is_err());
+}
+
+#[test]
+fn test_socks_proxy() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let (url, requests) = serve(vec![http_response("200 OK", &[], "through the proxy")]);
+    let server = url.trim_start_matches("http://").replace("/feed.xml", "");
+
+    // a SOCKS5 proxy without authentication, relaying one connection.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = format!("socks5://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut greeting = [0; 2];
+        client.read_exact(&mut greeting).unwrap();
+        let mut methods = vec![0; greeting[1] as usize];
+        client.read_exact(&mut methods).unwrap();
+        client.write_all(&[5, 0]).unwrap();
+
+        let mut connect = [0; 4];
+        client.read_exact(&mut connect).unwrap();
+        let address = match connect[3] {
+            1 => 4,
+            4 => 16,
+            _ => {
+                let mut length = [0; 1];
+                client.read_exact(&mut length).unwrap();
+                length[0] as usize
+            }
+        };
+        // the address and the port, the test server is the only target.
+        client.read_exact(&mut vec![0; address + 2]).unwrap();
+        let mut server = TcpStream::connect(server).unwrap();
+        client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        let mut upstream = server.try_clone().unwrap();
+        let mut downstream = client.try_clone().unwrap();
+        thread::spawn(move || std::io::copy(&mut downstream, &mut upstream));
+        std::io::copy(&mut server, &mut client).unwrap();
+    });
+
+    // ureq can miss a reply that comes before it waits for one, it then
+    // only notices it once the connect timeout is over.
+    let network = NetworkSettings {
+        proxy: Some(proxy),
+        connect_timeout: 1,
+        retries: 0,
+        ..NetworkSettings::default()
+    };
+    let response = send(|| get(&url, &network), &network).unwrap();
+    assert_eq!(response.into_string().unwrap(), "through the proxy");
+    assert!(requests.recv().unwrap().starts_with("GET /feed.xml "));
+}
@@ -3,16 +3,91 @@
 extern crate cursive;
 
 mod app;
+mod config;
 mod controller;
+mod date;
 mod db;
+mod download;
 mod feed;
+mod fetcher;
+mod filename;
+mod http;
+mod mp4;
+mod opml;
 mod player;
+mod retention;
+mod settings;
+mod sync;
+mod tags;
 mod ui;
 
+use config::Command;
+
 fn main() {
-    let controller = controller::Controller::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match config::load(&args, |name| std::env::var(name).ok()) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::Sync(config)) => return sync(&config),
+        Ok(Command::ImportOpml(config, path)) => return import_opml(&config, &path),
+        Ok(Command::ExportOpml(config, path)) => return export_opml(&config, path.as_deref()),
+        Ok(Command::Help) => return println!("{}", config::usage()),
+        Ok(Command::Version) => return println!("minicast {}", env!("CARGO_PKG_VERSION")),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let controller = controller::Controller::new(&config);
     match controller {
         Ok(mut controller) => controller.run(),
         Err(e) => println!("Error: {}", e),
     };
 }
+
+/// `minicast sync`: copy the downloads without starting the interface.
+fn sync(config: &config::Config) {
+    let result = app::App::new(config)
+        .and_then(|app| app.sync_job())
+        .and_then(|job| job.run());
+    match result {
+        Ok(report) => println!("Synced: {}", report),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `minicast opml import`: the new feeds are fetched on the next start.
+fn import_opml(config: &config::Config, path: &std::path::Path) {
+    let result = app::App::new(config).and_then(|app| {
+        let import = app.read_opml(path)?;
+        app.import_feeds(&import);
+        Ok(import)
+    });
+    match result {
+        Ok(import) => println!("{}", import.summary()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `minicast opml export`, to stdout without a file.
+fn export_opml(config: &config::Config, path: Option<&std::path::Path>) {
+    let result = app::App::new(config).and_then(|app| match path {
+        Some(path) => app.export_opml(path).map(|count| {
+            eprintln!("Exported {} feeds to {}", count, path.display());
+        }),
+        None => {
+            print!("{}", app.opml());
+            Ok(())
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
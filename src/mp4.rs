@@ -0,0 +1,340 @@
+//! Just enough of the MP4 file format to replace the iTunes style
+//! metadata of a file, the `moov/udta/meta/ilst` atoms.
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Mp4Error {
+    Io(io::Error),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for Mp4Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mp4Error::Io(e) => write!(f, "{}", e),
+            Mp4Error::Invalid(reason) => write!(f, "unusable MP4 file: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for Mp4Error {
+    fn from(e: io::Error) -> Self {
+        Mp4Error::Io(e)
+    }
+}
+
+/// The value of a metadata item.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Track(u16),
+    Jpeg(Vec<u8>),
+    Png(Vec<u8>),
+}
+
+/// A top level atom of the file.
+struct Atom {
+    start: u64,
+    /// header included.
+    size: u64,
+    header: u64,
+    kind: [u8; 4],
+}
+
+/// Replace the metadata of the file at `path` with `items`, as (atom
+/// name, value). The rest of the user data is kept.
+pub fn write_metadata(path: &Path, items: &[([u8; 4], Value)]) -> Result<(), Mp4Error> {
+    let mut file = File::open(path)?;
+    let atoms = top_level_atoms(&mut file)?;
+    if atoms.first().map(|atom| &atom.kind) != Some(b"ftyp") {
+        return Err(Mp4Error::Invalid("no ftyp atom"));
+    }
+    // track fragments can point at absolute offsets, leave them be.
+    if atoms.iter().any(|atom| &atom.kind == b"moof") {
+        return Err(Mp4Error::Invalid("fragmented files can't be tagged"));
+    }
+    let moov = atoms
+        .iter()
+        .find(|atom| &atom.kind == b"moov")
+        .ok_or(Mp4Error::Invalid("no moov atom"))?;
+
+    let mut content = vec![0; (moov.size - moov.header) as usize];
+    file.seek(SeekFrom::Start(moov.start + moov.header))?;
+    file.read_exact(&mut content)?;
+
+    let mut content = with_metadata(&content, items)?;
+    let size: u32 = (content.len() + 8)
+        .try_into()
+        .map_err(|_| Mp4Error::Invalid("the moov atom grows too large"))?;
+    // the media data after the moov atom moves by as much as it grows.
+    let moov_end = moov.start + moov.size;
+    shift_chunk_offsets(&mut content, moov_end, size as i64 - moov.size as i64)?;
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tagging");
+    let temporary = path.with_file_name(name);
+    let result = (|| {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&mut file).take(moov.start), &mut out)?;
+        out.write_all(&size.to_be_bytes())?;
+        out.write_all(b"moov")?;
+        out.write_all(&content)?;
+        file.seek(SeekFrom::Start(moov_end))?;
+        io::copy(&mut file, &mut out)?;
+        out.flush()?;
+        fs::rename(&temporary, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result.map_err(Mp4Error::from)
+}
+
+fn top_level_atoms(file: &mut File) -> Result<Vec<Atom>, Mp4Error> {
+    let length = file.metadata()?.len();
+    let mut atoms = vec![];
+    let mut start = 0;
+    while start + 8 <= length {
+        file.seek(SeekFrom::Start(start))?;
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let (size, header_size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // up to the end of the file.
+            0 => (length - start, 8),
+            // a 64 bit size follows the name.
+            1 => {
+                let mut size = [0; 8];
+                file.read_exact(&mut size)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_size || size > length - start {
+            return Err(Mp4Error::Invalid("an atom runs past the end of the file"));
+        }
+        atoms.push(Atom {
+            start,
+            size,
+            header: header_size,
+            kind: header[4..].try_into().unwrap(),
+        });
+        start += size;
+    }
+    Ok(atoms)
+}
+
+/// The atoms in `data`, as (start, end, name). The content of each is
+/// `data[start + 8..end]`.
+fn children(data: &[u8]) -> Result<Vec<(usize, usize, [u8; 4])>, Mp4Error> {
+    let mut atoms = vec![];
+    let mut start = 0;
+    while start + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[start..start + 4].try_into().unwrap()) as usize;
+        if size < 8 || size > data.len() - start {
+            return Err(Mp4Error::Invalid("an atom runs past its parent"));
+        }
+        let kind = data[start + 4..start + 8].try_into().unwrap();
+        atoms.push((start, start + size, kind));
+        start += size;
+    }
+    Ok(atoms)
+}
+
+/// `moov` with its user data holding a new `meta` atom of `items`.
+fn with_metadata(moov: &[u8], items: &[([u8; 4], Value)]) -> Result<Vec<u8>, Mp4Error> {
+    let mut content = Vec::with_capacity(moov.len());
+    let mut user_data = vec![];
+    for (start, end, kind) in children(moov)? {
+        if &kind != b"udta" {
+            content.extend_from_slice(&moov[start..end]);
+            continue;
+        }
+        let udta = &moov[start + 8..end];
+        for (start, end, kind) in children(udta)? {
+            if &kind != b"meta" {
+                user_data.extend_from_slice(&udta[start..end]);
+            }
+        }
+    }
+    user_data.extend(meta(items));
+    content.extend(atom(b"udta", &user_data));
+    Ok(content)
+}
+
+fn meta(items: &[([u8; 4], Value)]) -> Vec<u8> {
+    // version, flags and pre-defined, the handler, reserved and no name.
+    let mut handler = vec![0; 8];
+    handler.extend_from_slice(b"mdirappl");
+    handler.extend_from_slice(&[0; 9]);
+
+    let mut list = vec![];
+    for (name, value) in items {
+        let (kind, payload): (u32, &[u8]) = match value {
+            Value::Text(text) => (1, text.as_bytes()),
+            // track, then the total, unknown.
+            Value::Track(track) => {
+                let [high, low] = track.to_be_bytes();
+                (0, &[0, 0, high, low, 0, 0, 0, 0])
+            }
+            Value::Jpeg(data) => (13, data),
+            Value::Png(data) => (14, data),
+        };
+        // the type, then the locale.
+        let mut data = kind.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(payload);
+        list.extend(atom(name, &atom(b"data", &data)));
+    }
+
+    // version and flags.
+    let mut meta = vec![0; 4];
+    meta.extend(atom(b"hdlr", &handler));
+    meta.extend(atom(b"ilst", &list));
+    atom(b"meta", &meta)
+}
+
+fn atom(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut atom = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+    atom.extend_from_slice(kind);
+    atom.extend_from_slice(content);
+    atom
+}
+
+/// Move the chunk offsets of every track pointing at or past `from` by
+/// `delta` bytes.
+fn shift_chunk_offsets(data: &mut [u8], from: u64, delta: i64) -> Result<(), Mp4Error> {
+    for (start, end, kind) in children(data)? {
+        let content = &mut data[start + 8..end];
+        let width = match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                shift_chunk_offsets(content, from, delta)?;
+                continue;
+            }
+            b"stco" => 4,
+            b"co64" => 8,
+            _ => continue,
+        };
+
+        let invalid = Mp4Error::Invalid("a chunk offset table runs past its atom");
+        // version and flags, then the number of offsets.
+        let count = match content.get(4..8) {
+            Some(count) => u32::from_be_bytes(count.try_into().unwrap()) as usize,
+            None => return Err(invalid),
+        };
+        let offsets = content.get_mut(8..8 + count * width).ok_or(invalid)?;
+        for offset in offsets.chunks_mut(width) {
+            let value = match width {
+                4 => u32::from_be_bytes(offset.try_into().unwrap()) as u64,
+                _ => u64::from_be_bytes(offset.try_into().unwrap()),
+            };
+            if value < from {
+                continue;
+            }
+            let value = (value as i64 + delta) as u64;
+            match width {
+                4 => offset.copy_from_slice(
+                    &u32::try_from(value)
+                        .map_err(|_| Mp4Error::Invalid("a chunk moves past 4 GB"))?
+                        .to_be_bytes(),
+                ),
+                _ => offset.copy_from_slice(&value.to_be_bytes()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The content of the first `kind` atom in `data`.
+#[cfg(test)]
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    children(data)
+        .unwrap()
+        .into_iter()
+        .find(|(_, _, name)| name == kind)
+        .map(|(start, end, _)| &data[start + 8..end])
+}
+
+/// A file with one chunk of "audio", its moov atom before or after it.
+#[cfg(test)]
+fn test_file(moov_first: bool) -> Vec<u8> {
+    let ftyp = atom(b"ftyp", b"M4A \0\0\0\0M4A isom");
+    let moov = |offset: u32| {
+        let mut stco = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stco.extend_from_slice(&offset.to_be_bytes());
+        let stbl = atom(b"stbl", &atom(b"stco", &stco));
+        let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+
+        let mut old = vec![0; 4];
+        old.extend(atom(b"ilst", &atom(b"\xa9nam", &atom(b"data", b"track01"))));
+        let mut udta = atom(b"meta", &old);
+        udta.extend(atom(b"cprt", b"kept"));
+
+        let mut moov = atom(b"mvhd", &[0; 100]);
+        moov.extend(trak);
+        moov.extend(atom(b"udta", &udta));
+        atom(b"moov", &moov)
+    };
+    let mdat = atom(b"mdat", b"audio");
+
+    let moov_size = moov(0).len() as u32;
+    let ftyp_size = ftyp.len() as u32;
+    let mut file = ftyp;
+    if moov_first {
+        file.extend(moov(ftyp_size + moov_size + 8));
+        file.extend(mdat);
+    } else {
+        file.extend(mdat);
+        file.extend(moov(ftyp_size + 8));
+    }
+    file
+}
+
+#[test]
+fn test_write_metadata() {
+    let dir = std::env::temp_dir().join(format!("minicast-mp4-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("episode.m4a");
+    let items = vec![
+        (*b"\xa9nam", Value::Text("Episode 12".into())),
+        (*b"trkn", Value::Track(12)),
+        (*b"covr", Value::Png(b"\x89PNG".to_vec())),
+    ];
+
+    for moov_first in [true, false] {
+        fs::write(&path, test_file(moov_first)).unwrap();
+        write_metadata(&path, &items).unwrap();
+        let file = fs::read(&path).unwrap();
+
+        let moov = child(&file, b"moov").unwrap();
+        let trak = child(moov, b"trak").unwrap();
+        let minf = child(child(trak, b"mdia").unwrap(), b"minf").unwrap();
+        let stbl = child(minf, b"stbl").unwrap();
+        let offset = u32::from_be_bytes(child(stbl, b"stco").unwrap()[8..12].try_into().unwrap());
+        assert_eq!(&file[offset as usize..offset as usize + 5], b"audio");
+
+        let udta = child(moov, b"udta").unwrap();
+        assert_eq!(child(udta, b"cprt"), Some(&b"kept"[..]));
+        let list = child(&child(udta, b"meta").unwrap()[4..], b"ilst").unwrap();
+        let title = child(child(list, b"\xa9nam").unwrap(), b"data").unwrap();
+        assert_eq!(&title[8..], b"Episode 12");
+        let track = child(child(list, b"trkn").unwrap(), b"data").unwrap();
+        assert_eq!(&track[8..], &[0, 0, 0, 12, 0, 0, 0, 0]);
+        assert_eq!(
+            &child(child(list, b"covr").unwrap(), b"data").unwrap()[..4],
+            &[0, 0, 0, 14]
+        );
+    }
+
+    fs::write(&path, b"audio").unwrap();
+    assert!(matches!(
+        write_metadata(&path, &items),
+        Err(Mp4Error::Invalid(_))
+    ));
+    fs::remove_file(&path).unwrap();
+}
@@ -1,8 +1,11 @@
+use crate::settings::NetworkSettings;
 use libmpv::{Mpv, Result};
 
 pub struct MediaWorker {
     handler: Mpv,
     pub is_paused: bool,
+    /// url of the last loaded file.
+    loaded: String,
 }
 
 pub fn seconds_to_hms(position: i64) -> String {
@@ -19,15 +22,65 @@ impl MediaWorker {
         handler
             .set_property("vid", "no")
             .expect("Failed to set option 'vid' to 'no'");
+        // stay on the last frame at the end, so the final position can
+        // still be read and saved.
+        handler
+            .set_property("keep-open", "yes")
+            .expect("Failed to set option 'keep-open' to 'yes'");
 
         Ok(Self {
             handler,
             is_paused: false,
+            loaded: String::new(),
         })
     }
 
     pub fn loadfile(&mut self, url: &str) -> Result<()> {
+        self.loadfile_at(url, 0.0)
+    }
+
+    /// Use the user agent, proxy and read timeout of the network settings
+    /// for streams. mpv only speaks to http proxies: behind a SOCKS one,
+    /// streams connect directly.
+    pub fn set_network(&mut self, network: &NetworkSettings) -> Result<()> {
+        self.handler
+            .set_property("user-agent", network.user_agent.as_str())?;
+        if let Some(proxy) = &network.proxy {
+            match proxy.split_once("://") {
+                None => self
+                    .handler
+                    .set_property("http-proxy", format!("http://{}", proxy))?,
+                Some(("http", _)) => self.handler.set_property("http-proxy", proxy.as_str())?,
+                Some(_) => {}
+            }
+        }
+        if network.read_timeout > 0 {
+            self.handler
+                .set_property("network-timeout", network.read_timeout as i64)?;
+        }
+        Ok(())
+    }
+
+    /// Send an `Authorization` header with the files loaded from now on,
+    /// for enclosures of private feeds. mpv sends it to every server a
+    /// redirect leads to as well, only pass it for enclosures on the
+    /// feed's own server.
+    pub fn set_authorization(&mut self, authorization: Option<&str>) -> Result<()> {
+        let header = match authorization {
+            Some(authorization) => format!("Authorization: {}", authorization),
+            None => String::new(),
+        };
+        self.handler.set_property("http-header-fields", header)?;
+        Ok(())
+    }
+
+    /// Load a file and start playing it `start` seconds in.
+    pub fn loadfile_at(&mut self, url: &str, start: f64) -> Result<()> {
+        // `start` is an option, it applies to every file loaded after it.
+        self.handler
+            .set_property("start", format!("{}", start.max(0.0)))?;
         self.handler.command("loadfile", &[url])?;
+        self.loaded = url.into();
         Ok(())
     }
 
@@ -72,6 +125,26 @@ impl MediaWorker {
         ))
     }
 
+    /// `time-pos` and `duration` in seconds.
+    pub fn playback(&self) -> Result<(f64, f64)> {
+        let pos: f64 = self.handler.get_property("time-pos")?;
+        let duration: f64 = self.handler.get_property("duration")?;
+        Ok((pos, duration))
+    }
+
+    /// Whether the loaded file played to its end, needs `keep-open`.
+    /// Until mpv switched to the file `loadfile` asked for, `eof-reached`
+    /// still describes the previous one and is ignored.
+    pub fn eof_reached(&self) -> bool {
+        match self.handler.get_property::<String>("path") {
+            Ok(path) if path == self.loaded => self
+                .handler
+                .get_property::<bool>("eof-reached")
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
     fn time_seek(&mut self, f: impl FnOnce(i64) -> i64) -> Result<()> {
         let pos: i64 = self.handler.get_property("time-pos")?;
         self.handler.set_property("time-pos", f(pos))?;
@@ -90,6 +163,6 @@ impl MediaWorker {
 
     pub fn percentage(&self) -> Result<f64> {
         let percent: f64 = self.handler.get_property("percent-pos")?;
-        Ok(percent as f64)
+        Ok(percent)
     }
 }
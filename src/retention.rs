@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+use std::fmt;
+
+/// What is downloaded and kept of a feed without asking.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedRules {
+    /// Download the newest this many unplayed episodes, 0 turns it off.
+    pub auto_download: u32,
+    /// Delete downloads once they are played.
+    pub delete_played: bool,
+    /// Keep at most this many downloads, the newest.
+    pub keep_episodes: Option<u32>,
+    /// Keep at most this many bytes of downloads, the newest.
+    pub keep_bytes: Option<u64>,
+}
+
+impl FeedRules {
+    pub fn is_empty(&self) -> bool {
+        *self == FeedRules::default()
+    }
+}
+
+/// What is on disk for an episode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stored {
+    /// never downloaded.
+    Nothing,
+    /// queued, downloading, failed or deleted: left alone.
+    Tracked,
+    /// downloaded, this many bytes.
+    File(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct EpisodeState {
+    pub id: u32,
+    pub pubdate: Option<i64>,
+    pub played: bool,
+    pub stored: Stored,
+}
+
+/// A feed and its episodes, newest first.
+#[derive(Debug, Clone)]
+pub struct FeedState {
+    pub rules: FeedRules,
+    pub episodes: Vec<EpisodeState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    Played,
+    KeepEpisodes(u32),
+    KeepBytes(u64),
+    Quota,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Played => write!(f, "played"),
+            Reason::KeepEpisodes(count) => write!(f, "keeps {} episodes", count),
+            Reason::KeepBytes(bytes) => write!(f, "keeps {} MB", bytes / 1_000_000),
+            Reason::Quota => write!(f, "over the disk quota"),
+        }
+    }
+}
+
+/// Episodes to download and downloads to delete.
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    pub download: Vec<u32>,
+    pub delete: Vec<(u32, Reason)>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.download.is_empty() && self.delete.is_empty()
+    }
+}
+
+/// Apply the rules of every feed, then delete the oldest downloads,
+/// played ones first, until they fit in `quota` bytes. `protected`
+/// episodes (playing, queued) are never deleted.
+///
+/// Only episodes that were never downloaded are downloaded, so a download
+/// deleted by a rule doesn't come back.
+pub fn plan(feeds: &[FeedState], quota: Option<u64>, protected: &HashSet<u32>) -> Plan {
+    let mut plan = Plan::default();
+    // downloads that survived the feed rules.
+    let mut kept: Vec<&EpisodeState> = vec![];
+
+    for feed in feeds {
+        let rules = &feed.rules;
+        let mut kept_count = 0;
+        let mut kept_bytes = 0;
+
+        for episode in &feed.episodes {
+            let size = match episode.stored {
+                Stored::File(size) => size,
+                _ => continue,
+            };
+            let reason = match (rules.keep_episodes, rules.keep_bytes) {
+                _ if protected.contains(&episode.id) => None,
+                _ if rules.delete_played && episode.played => Some(Reason::Played),
+                (Some(count), _) if kept_count >= count => Some(Reason::KeepEpisodes(count)),
+                (_, Some(bytes)) if kept_bytes + size > bytes => Some(Reason::KeepBytes(bytes)),
+                _ => None,
+            };
+            match reason {
+                Some(reason) => plan.delete.push((episode.id, reason)),
+                None => {
+                    kept_count += 1;
+                    kept_bytes += size;
+                    kept.push(episode);
+                }
+            }
+        }
+
+        // more than it may keep would be deleted right away.
+        let newest = rules
+            .auto_download
+            .min(rules.keep_episodes.unwrap_or(u32::MAX));
+        plan.download.extend(
+            feed.episodes
+                .iter()
+                .filter(|episode| !episode.played)
+                .take(newest as usize)
+                .filter(|episode| episode.stored == Stored::Nothing)
+                .map(|episode| episode.id),
+        );
+    }
+
+    if let Some(quota) = quota {
+        let mut total: u64 = kept.iter().map(|episode| file_size(episode)).sum();
+        kept.sort_by_key(|episode| (!episode.played, episode.pubdate));
+        for episode in kept {
+            if total <= quota {
+                break;
+            }
+            if protected.contains(&episode.id) {
+                continue;
+            }
+            total -= file_size(episode);
+            plan.delete.push((episode.id, Reason::Quota));
+        }
+    }
+
+    plan
+}
+
+fn file_size(episode: &EpisodeState) -> u64 {
+    match episode.stored {
+        Stored::File(size) => size,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+fn test_feed(rules: FeedRules, episodes: &[(u32, bool, Stored)]) -> FeedState {
+    FeedState {
+        rules,
+        episodes: episodes
+            .iter()
+            .map(|&(id, played, stored)| EpisodeState {
+                id,
+                // newest first, as the feeds come out of the database.
+                pubdate: Some(1000 - id as i64),
+                played,
+                stored,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_plan_feed_rules() {
+    let mb = 1_000_000;
+    let feeds = [
+        test_feed(
+            FeedRules {
+                delete_played: true,
+                keep_episodes: Some(2),
+                ..FeedRules::default()
+            },
+            &[
+                (1, true, Stored::File(mb)),
+                (2, false, Stored::File(mb)),
+                (3, false, Stored::File(mb)),
+                (4, false, Stored::File(mb)),
+                (5, false, Stored::Tracked),
+            ],
+        ),
+        test_feed(
+            FeedRules {
+                keep_bytes: Some(3 * mb),
+                ..FeedRules::default()
+            },
+            &[
+                (11, true, Stored::File(2 * mb)),
+                (12, false, Stored::File(2 * mb)),
+            ],
+        ),
+    ];
+
+    let plan = plan(&feeds, None, &HashSet::new());
+    assert!(plan.download.is_empty());
+    assert_eq!(
+        plan.delete,
+        vec![
+            (1, Reason::Played),
+            (4, Reason::KeepEpisodes(2)),
+            (12, Reason::KeepBytes(3 * mb)),
+        ]
+    );
+}
+
+#[test]
+fn test_plan_auto_download() {
+    let feeds = [test_feed(
+        FeedRules {
+            auto_download: 3,
+            keep_episodes: Some(2),
+            ..FeedRules::default()
+        },
+        &[
+            (1, true, Stored::Nothing),
+            (2, false, Stored::File(1)),
+            (3, false, Stored::Nothing),
+            (4, false, Stored::Nothing),
+        ],
+    )];
+    // played episodes are skipped, keep_episodes caps the count.
+    assert_eq!(plan(&feeds, None, &HashSet::new()).download, vec![3]);
+
+    let feeds = [test_feed(
+        FeedRules {
+            auto_download: 2,
+            ..FeedRules::default()
+        },
+        &[(1, false, Stored::Tracked), (2, false, Stored::Nothing)],
+    )];
+    // deleted or failed downloads aren't retried.
+    assert_eq!(plan(&feeds, None, &HashSet::new()).download, vec![2]);
+}
+
+#[test]
+fn test_plan_quota() {
+    let feeds = [
+        test_feed(
+            FeedRules::default(),
+            &[
+                (1, false, Stored::File(10)),
+                (2, false, Stored::File(10)),
+                (3, true, Stored::File(10)),
+            ],
+        ),
+        test_feed(FeedRules::default(), &[(9, false, Stored::File(10))]),
+    ];
+
+    // played first, then the oldest, the playing episode stays.
+    let protected = [9].iter().copied().collect();
+    assert_eq!(
+        plan(&feeds, Some(15), &protected).delete,
+        vec![(3, Reason::Quota), (2, Reason::Quota), (1, Reason::Quota)]
+    );
+    assert!(plan(&feeds, Some(40), &protected).is_empty());
+}
@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+/// User tunable behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Fraction of an episode after which it counts as played.
+    pub played_threshold: f64,
+    /// Seconds between automatic refreshes of a feed, 0 turns them off.
+    /// Feeds can override it.
+    pub refresh_interval: u32,
+    /// Where episodes are downloaded to.
+    pub download_dir: PathBuf,
+    /// Path of a download in `download_dir`, see `filename::render`.
+    pub filename_template: String,
+    /// How many episodes are downloaded at the same time.
+    pub parallel_downloads: usize,
+    /// Bytes all downloads together may take, the oldest go first.
+    pub download_quota: Option<u64>,
+    /// Folder or mounted player downloads are synced to.
+    pub sync_dir: Option<PathBuf>,
+    /// Path of a synced episode in `sync_dir`, like `filename_template`.
+    pub sync_template: String,
+    pub network: NetworkSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            played_threshold: 0.95,
+            refresh_interval: 60 * 60,
+            download_dir: PathBuf::from("episodes"),
+            filename_template: "{feed}/{date} - {title}.{ext}".into(),
+            parallel_downloads: 2,
+            download_quota: None,
+            sync_dir: None,
+            sync_template: "{feed}/{date} - {title}.{ext}".into(),
+            network: NetworkSettings::default(),
+        }
+    }
+}
+
+/// How minicast talks to the network, for feeds, downloads and mpv.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkSettings {
+    /// `host:port` of an http proxy, with an optional `user:password@`,
+    /// or the same behind `socks5://` (also `socks4://`, `socks4a://`).
+    pub proxy: Option<String>,
+    /// Seconds to wait for a connection.
+    pub connect_timeout: u64,
+    /// Seconds to wait for the next bytes of a response. 0 waits forever.
+    pub read_timeout: u64,
+    /// How many times a failed request is retried.
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled for every next one.
+    pub retry_delay: u64,
+    pub user_agent: String,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            proxy: None,
+            connect_timeout: 10,
+            read_timeout: 30,
+            retries: 2,
+            retry_delay: 1000,
+            user_agent: format!("minicast/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Which episode every synced file is, in the target directory.
+const MANIFEST: &str = ".minicast-sync";
+/// The playlist of the synced episodes, in the target directory.
+pub const PLAYLIST: &str = "minicast.m3u";
+
+/// A downloaded episode to have in the target directory.
+#[derive(Debug, Clone)]
+pub struct SyncItem {
+    pub episode_id: u32,
+    pub source: PathBuf,
+    /// relative to the target directory.
+    pub target: PathBuf,
+    /// shown by players reading the playlist.
+    pub title: String,
+    pub duration: Option<u32>,
+}
+
+/// A file synced before, as the manifest remembers it.
+#[derive(Debug, Clone, PartialEq)]
+struct Synced {
+    path: PathBuf,
+    checksum: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncReport {
+    pub copied: usize,
+    /// already there with the same checksum.
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} copied, {} unchanged, {} removed",
+            self.copied, self.unchanged, self.removed
+        )
+    }
+}
+
+/// A sync worked out from the library, to run away from it.
+pub struct SyncJob {
+    pub dir: PathBuf,
+    pub items: Vec<SyncItem>,
+    /// episodes whose synced files stay when they aren't in `items`.
+    pub keep: HashSet<u32>,
+}
+
+impl SyncJob {
+    pub fn run(&self) -> Result<SyncReport, String> {
+        sync(&self.dir, &self.items, |id| !self.keep.contains(&id))
+            .map_err(|e| format!("couldn't sync to {}: {}", self.dir.display(), e))
+    }
+}
+
+/// Copy `items` into `dir` and write a playlist of them. Files synced
+/// before whose episodes aren't in `items` are removed when `remove` says
+/// so (the episode was played) and kept otherwise.
+pub fn sync<F>(dir: &Path, items: &[SyncItem], remove: F) -> io::Result<SyncReport>
+where
+    F: Fn(u32) -> bool,
+{
+    fs::create_dir_all(dir)?;
+    let mut manifest = read_manifest(dir)?;
+    let mut report = SyncReport::default();
+
+    for item in items {
+        let crc = checksum(&item.source)?;
+        let target = dir.join(&item.target);
+        let previous = manifest.remove(&item.episode_id);
+
+        // the template changed since the last sync.
+        if let Some(previous) = &previous {
+            if previous.path != item.target {
+                remove_file(dir, &previous.path)?;
+            }
+        }
+
+        let unchanged = match previous {
+            Some(previous) if previous.path == item.target => {
+                previous.checksum == crc && same_size(&item.source, &target)
+            }
+            // a file the manifest doesn't know, maybe copied by hand.
+            _ => same_size(&item.source, &target) && checksum(&target)? == crc,
+        };
+        if unchanged {
+            report.unchanged += 1;
+        } else {
+            copy(&item.source, &target)?;
+            report.copied += 1;
+        }
+
+        let synced = Synced {
+            path: item.target.clone(),
+            checksum: crc,
+        };
+        manifest.insert(item.episode_id, synced);
+    }
+
+    // what is left was synced before and isn't asked for now.
+    let leftover: Vec<u32> = manifest
+        .keys()
+        .copied()
+        .filter(|id| !items.iter().any(|item| item.episode_id == *id))
+        .collect();
+    for id in leftover {
+        if remove(id) {
+            let synced = manifest.remove(&id).unwrap();
+            remove_file(dir, &synced.path)?;
+            report.removed += 1;
+        }
+    }
+
+    write_playlist(dir, items, &manifest)?;
+    write_manifest(dir, &manifest)?;
+    Ok(report)
+}
+
+/// CRC-32 of a file's content.
+fn checksum(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+fn same_size(source: &Path, target: &Path) -> bool {
+    match (fs::metadata(source), fs::metadata(target)) {
+        (Ok(source), Ok(target)) => source.len() == target.len(),
+        _ => false,
+    }
+}
+
+/// Copy through a temporary file, so a full or unplugged device doesn't
+/// leave half a file that looks synced.
+fn copy(source: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = crate::download::partial_path(target);
+    fs::copy(source, &partial)?;
+    fs::rename(&partial, target)
+}
+
+/// Remove a synced file and the directories it leaves empty.
+fn remove_file(dir: &Path, path: &Path) -> io::Result<()> {
+    match fs::remove_file(dir.join(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for parent in path.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || fs::remove_dir(dir.join(parent)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_manifest(dir: &Path) -> io::Result<HashMap<u32, Synced>> {
+    let file = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut manifest = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.splitn(3, '\t');
+        let (id, checksum, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(checksum), Some(path)) => (id, checksum, path),
+            _ => continue,
+        };
+        if let (Ok(id), Ok(checksum)) = (id.parse(), u32::from_str_radix(checksum, 16)) {
+            let path = PathBuf::from(path);
+            manifest.insert(id, Synced { path, checksum });
+        }
+    }
+    Ok(manifest)
+}
+
+fn write_manifest(dir: &Path, manifest: &HashMap<u32, Synced>) -> io::Result<()> {
+    let mut ids: Vec<&u32> = manifest.keys().collect();
+    ids.sort();
+    let mut content = String::new();
+    for id in ids {
+        let synced = &manifest[id];
+        content.push_str(&format!(
+            "{}\t{:08x}\t{}\n",
+            id,
+            synced.checksum,
+            synced.path.display()
+        ));
+    }
+    fs::write(dir.join(MANIFEST), content)
+}
+
+/// An extended M3U of `items`, then the files kept from earlier syncs.
+fn write_playlist(
+    dir: &Path,
+    items: &[SyncItem],
+    manifest: &HashMap<u32, Synced>,
+) -> io::Result<()> {
+    let mut file = io::BufWriter::new(File::create(dir.join(PLAYLIST))?);
+    writeln!(file, "#EXTM3U")?;
+    for item in items {
+        let duration = item.duration.map_or(-1, |duration| duration as i64);
+        writeln!(
+            file,
+            "#EXTINF:{},{}",
+            duration,
+            item.title.replace('\n', " ")
+        )?;
+        writeln!(file, "{}", playlist_path(&item.target))?;
+    }
+
+    let mut kept: Vec<&Synced> = manifest
+        .iter()
+        .filter(|(id, _)| !items.iter().any(|item| item.episode_id == **id))
+        .map(|(_, synced)| synced)
+        .collect();
+    kept.sort_by(|a, b| a.path.cmp(&b.path));
+    for synced in kept {
+        writeln!(file, "{}", playlist_path(&synced.path))?;
+    }
+    file.flush()
+}
+
+/// Players expect `/` between directories, whatever the platform.
+fn playlist_path(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+#[cfg(test)]
+fn test_item(dir: &Path, id: u32, content: &str) -> SyncItem {
+    let source = dir.join(format!("source/{}.mp3", id));
+    fs::create_dir_all(source.parent().unwrap()).unwrap();
+    fs::write(&source, content).unwrap();
+    SyncItem {
+        episode_id: id,
+        source,
+        target: PathBuf::from(format!("Show/{}.mp3", id)),
+        title: format!("Show - Episode {}", id),
+        duration: Some(60 * id),
+    }
+}
+
+#[test]
+fn test_sync() {
+    let dir = std::env::temp_dir().join(format!("minicast-sync-{}", std::process::id()));
+    let target = dir.join("player");
+    let items = vec![test_item(&dir, 1, "one"), test_item(&dir, 2, "two")];
+
+    let report = sync(&target, &items, |_| false).unwrap();
+    assert_eq!(report.copied, 2);
+    assert_eq!(
+        fs::read_to_string(target.join("Show/2.mp3")).unwrap(),
+        "two"
+    );
+    assert_eq!(
+        fs::read_to_string(target.join(PLAYLIST)).unwrap(),
+        "#EXTM3U\n\
+         #EXTINF:60,Show - Episode 1\nShow/1.mp3\n\
+         #EXTINF:120,Show - Episode 2\nShow/2.mp3\n"
+    );
+
+    // unchanged files are skipped, changed ones copied again.
+    fs::write(&items[1].source, "TWO").unwrap();
+    let report = sync(&target, &items, |_| false).unwrap();
+    assert_eq!((report.copied, report.unchanged), (1, 1));
+    assert_eq!(
+        fs::read_to_string(target.join("Show/2.mp3")).unwrap(),
+        "TWO"
+    );
+
+    // episode 1 was played, episode 2 isn't asked for but kept.
+    let items = vec![test_item(&dir, 3, "three")];
+    let report = sync(&target, &items, |id| id == 1).unwrap();
+    assert_eq!((report.copied, report.removed), (1, 1));
+    assert!(!target.join("Show/1.mp3").exists());
+    assert!(target.join("Show/2.mp3").exists());
+    assert_eq!(
+        fs::read_to_string(target.join(PLAYLIST)).unwrap(),
+        "#EXTM3U\n#EXTINF:180,Show - Episode 3\nShow/3.mp3\nShow/2.mp3\n"
+    );
+
+    // the folder goes with its last episode.
+    sync(&target, &[], |_| true).unwrap();
+    assert!(!target.join("Show").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_sync_file_copied_by_hand() {
+    let dir = std::env::temp_dir().join(format!("minicast-sync-hand-{}", std::process::id()));
+    let target = dir.join("player");
+    let items = vec![test_item(&dir, 1, "one")];
+    fs::create_dir_all(target.join("Show")).unwrap();
+    fs::write(target.join("Show/1.mp3"), "one").unwrap();
+
+    let report = sync(&target, &items, |_| false).unwrap();
+    assert_eq!((report.copied, report.unchanged), (0, 1));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,273 @@
+use crate::http;
+use crate::mp4::{self, Mp4Error};
+use crate::settings::NetworkSettings;
+use id3::frame::{Comment, Picture, PictureType};
+use id3::{Tag, TagLike, Timestamp, Version};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+/// Artwork larger than this is left out of the tags.
+const MAX_ARTWORK: usize = 5_000_000;
+
+/// What a downloaded episode is tagged with, from the feed and the episode
+/// in the database.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tags {
+    /// the show.
+    pub album: String,
+    pub artist: String,
+    pub title: String,
+    /// publication date as a unix timestamp.
+    pub date: Option<i64>,
+    pub track: Option<u32>,
+    /// the episode description, as plain text.
+    pub comment: String,
+    /// url of the channel artwork, fetched when tagging.
+    pub artwork: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum TagError {
+    Id3(id3::Error),
+    Mp4(Mp4Error),
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagError::Id3(e) => write!(f, "couldn't write the tags: {}", e),
+            TagError::Mp4(e) => write!(f, "couldn't write the tags: {}", e),
+        }
+    }
+}
+
+impl From<id3::Error> for TagError {
+    fn from(e: id3::Error) -> Self {
+        TagError::Id3(e)
+    }
+}
+
+impl From<Mp4Error> for TagError {
+    fn from(e: Mp4Error) -> Self {
+        TagError::Mp4(e)
+    }
+}
+
+/// Replace the tags of the file at `path`, in the format `extension` (the
+/// one of the finished file, `path` can still be a partial download) calls
+/// for. MP3 files get ID3 tags, MP4 files iTunes style metadata, returns
+/// false for the formats left alone. Artwork that can't be fetched is
+/// skipped.
+pub fn write(
+    path: &Path,
+    extension: &str,
+    tags: &Tags,
+    network: &NetworkSettings,
+) -> Result<bool, TagError> {
+    let extension = extension.to_ascii_lowercase();
+    let is_mp4 = matches!(extension.as_str(), "m4a" | "m4b" | "mp4" | "m4v");
+    if extension != "mp3" && !is_mp4 {
+        return Ok(false);
+    }
+
+    let artwork = tags
+        .artwork
+        .as_ref()
+        .and_then(|url| fetch_artwork(url, network));
+    // whatever came with the file is replaced.
+    if is_mp4 {
+        mp4::write_metadata(path, &mp4_items(tags, artwork))?;
+    } else {
+        let mut tag = id3_tag(tags);
+        if let Some(picture) = artwork {
+            tag.add_frame(picture);
+        }
+        tag.write_to_path(path, Version::Id3v24)?;
+    }
+    Ok(true)
+}
+
+fn id3_tag(tags: &Tags) -> Tag {
+    let mut tag = Tag::new();
+    tag.set_album(tags.album.as_str());
+    if !tags.artist.is_empty() {
+        tag.set_artist(tags.artist.as_str());
+        tag.set_album_artist(tags.artist.as_str());
+    }
+    tag.set_title(tags.title.as_str());
+    tag.set_genre("Podcast");
+    if let Some(track) = tags.track {
+        tag.set_track(track);
+    }
+    if let Some(timestamp) = tags.date.and_then(id3_timestamp) {
+        tag.set_date_recorded(timestamp);
+    }
+    if !tags.comment.is_empty() {
+        tag.add_frame(Comment {
+            lang: "eng".into(),
+            description: String::new(),
+            text: tags.comment.clone(),
+        });
+    }
+    tag
+}
+
+fn mp4_items(tags: &Tags, artwork: Option<Picture>) -> Vec<([u8; 4], mp4::Value)> {
+    let text = |text: &str| mp4::Value::Text(text.to_string());
+    let mut items = vec![
+        (*b"\xa9alb", text(&tags.album)),
+        (*b"\xa9nam", text(&tags.title)),
+        (*b"\xa9gen", text("Podcast")),
+    ];
+    if !tags.artist.is_empty() {
+        items.push((*b"\xa9ART", text(&tags.artist)));
+        items.push((*b"aART", text(&tags.artist)));
+    }
+    if let Some(track) = tags.track.and_then(|track| u16::try_from(track).ok()) {
+        items.push((*b"trkn", mp4::Value::Track(track)));
+    }
+    if let Some(date) = tags.date {
+        items.push((*b"\xa9day", text(&crate::date::format_date(date))));
+    }
+    if !tags.comment.is_empty() {
+        items.push((*b"\xa9cmt", text(&tags.comment)));
+    }
+    // the only image formats MP4 metadata has.
+    match artwork {
+        Some(picture) if picture.mime_type == "image/png" => {
+            items.push((*b"covr", mp4::Value::Png(picture.data)))
+        }
+        Some(picture) if picture.mime_type == "image/jpeg" => {
+            items.push((*b"covr", mp4::Value::Jpeg(picture.data)))
+        }
+        _ => {}
+    }
+    items
+}
+
+fn id3_timestamp(timestamp: i64) -> Option<Timestamp> {
+    crate::date::format_date(timestamp).parse().ok()
+}
+
+fn fetch_artwork(url: &str, network: &NetworkSettings) -> Option<Picture> {
+    let (response, _) = http::follow(url, None, network, |http| http).ok()?;
+    if response.status() != 200 {
+        return None;
+    }
+    let content_type = response.header("content-type").map(String::from);
+    let mut data = vec![];
+    response
+        .into_reader()
+        .take(MAX_ARTWORK as u64 + 1)
+        .read_to_end(&mut data)
+        .ok()?;
+    if data.len() > MAX_ARTWORK {
+        return None;
+    }
+    let mime_type = match content_type {
+        Some(mime) if mime.starts_with("image/") => mime.split(';').next()?.trim().to_string(),
+        _ if data.starts_with(b"\x89PNG") => "image/png".into(),
+        _ if data.starts_with(b"\xff\xd8") => "image/jpeg".into(),
+        _ => return None,
+    };
+    Some(Picture {
+        mime_type,
+        picture_type: PictureType::CoverFront,
+        description: String::new(),
+        data,
+    })
+}
+
+/// Episode descriptions are often HTML, drop the markup and the common
+/// entities for the comment.
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn test_plain_text() {
+    assert_eq!(
+        plain_text("<p>Guests &amp; news</p><p>Links:<br/><a href=\"x\">site</a></p>"),
+        "Guests & news Links: site"
+    );
+    assert_eq!(plain_text("no markup"), "no markup");
+}
+
+#[test]
+fn test_write_tags() {
+    let dir = std::env::temp_dir().join(format!("minicast-tags-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (url, _requests) = http::serve(vec![http::http_response(
+        "200 OK",
+        &["Content-Type: image/png"],
+        "\u{89}PNG",
+    )]);
+    let tags = Tags {
+        album: "The Show".into(),
+        artist: "Someone".into(),
+        title: "Episode 12".into(),
+        date: Some(1_600_000_000),
+        track: Some(12),
+        comment: "About things".into(),
+        artwork: Some(url),
+    };
+    let network = NetworkSettings {
+        retries: 0,
+        ..NetworkSettings::default()
+    };
+
+    // garbage tags that came with the file are replaced.
+    let path = dir.join("episode.mp3");
+    let mut old = Tag::new();
+    old.set_title("track01");
+    old.set_album("Unknown");
+    std::fs::write(&path, b"").unwrap();
+    old.write_to_path(&path, Version::Id3v24).unwrap();
+
+    assert!(write(&path, "mp3", &tags, &network).unwrap());
+    let tag = Tag::read_from_path(&path).unwrap();
+    assert_eq!(tag.album(), Some("The Show"));
+    assert_eq!(tag.title(), Some("Episode 12"));
+    assert_eq!(tag.artist(), Some("Someone"));
+    assert_eq!(tag.track(), Some(12));
+    assert_eq!(
+        tag.date_recorded().map(|d| d.to_string()),
+        Some("2020-09-13".into())
+    );
+    assert_eq!(tag.comments().next().unwrap().text, "About things");
+    assert_eq!(tag.pictures().next().unwrap().mime_type, "image/png");
+    std::fs::remove_file(&path).unwrap();
+
+    // MP4 files are covered in mp4.rs, anything else is left alone.
+    let path = dir.join("episode.ogg");
+    std::fs::write(&path, b"audio").unwrap();
+    assert!(!write(&path, "ogg", &tags, &network).unwrap());
+    assert_eq!(std::fs::read(&path).unwrap(), b"audio");
+    assert!(matches!(
+        write(&path, "m4a", &tags, &network),
+        Err(TagError::Mp4(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,7 +1,13 @@
+use crate::config;
 use crate::controller::ControllerMessage;
+use crate::feed::Credentials;
+use crate::retention::FeedRules;
 use cursive::event::Key;
+use cursive::theme::ColorStyle;
+use cursive::utils::markup::StyledString;
 use cursive::view::{Nameable, Resizable, Scrollable, SizeConstraint};
 use cursive::views::*;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
 pub struct Ui {
@@ -13,19 +19,39 @@ pub struct Ui {
 
 pub enum UiMessage {
     UpdatePlaying(String, String),
+    UpdatePlayingExtras(String),
+    UpdateFeedDetails(String),
     UpdateProgress(usize, String),
     UpdateFeeds(Vec<(String, u32)>),
-    UpdateEpisodes(Vec<(String, u32)>),
+    UpdateEpisodes(Vec<(String, u32, bool)>),
+    UpdateQueue(Vec<(String, u32)>),
+    ShowError(String),
+    ShowInfo(String, String),
+    /// preview of an OPML import, to confirm.
+    ShowImport(String),
+    /// the refresh interval of a feed, to edit.
+    ShowRefreshInterval(u32, Option<u32>),
+    /// the download rules of a feed, to edit.
+    ShowFeedRules(u32, FeedRules),
 }
 
 impl Ui {
     /// Create a new Ui object.  The provided `mpsc` sender will be used
-    /// by the UI to send messages to the controller.
-    pub fn new(controller_tx: mpsc::Sender<ControllerMessage>) -> Ui {
+    /// by the UI to send messages to the controller, `theme` is a cursive
+    /// theme file replacing the built-in one.
+    pub fn new(
+        controller_tx: mpsc::Sender<ControllerMessage>,
+        theme: Option<&Path>,
+    ) -> Result<Ui, String> {
         let (ui_tx, ui_rx) = mpsc::channel::<UiMessage>();
 
         let mut siv = cursive::CursiveRunnable::default();
-        siv.load_theme_file("themes/style.toml").unwrap();
+        match theme {
+            Some(path) => siv
+                .load_theme_file(path)
+                .map_err(|e| format!("couldn't load theme {}: {:?}", path.display(), e))?,
+            None => siv.load_toml(config::DEFAULT_THEME).unwrap(),
+        }
         let cursive = siv.into_runner();
 
         let mut ui = Ui {
@@ -44,6 +70,7 @@ impl Ui {
 
         let mut feeds_select: SelectView<u32> = SelectView::new().autojump();
         let mut episodes_select: SelectView<u32> = SelectView::new();
+        let mut queue_select: SelectView<u32> = SelectView::new();
 
         // Configure callback for feeds list
         let controller_tx_clone = ui.controller_tx.clone();
@@ -61,17 +88,33 @@ impl Ui {
                 .unwrap();
         });
 
+        let controller_tx_clone = ui.controller_tx.clone();
+        queue_select.set_on_submit(move |_, id: &u32| {
+            controller_tx_clone
+                .send(ControllerMessage::UpdatePlayEpisode(*id))
+                .unwrap();
+        });
+
         ui.add_global_callbacks();
 
         let controller_tx_clone = ui.controller_tx.clone();
+        let interval_tx = ui.controller_tx.clone();
+        let import_tx = ui.controller_tx.clone();
+        let export_tx = ui.controller_tx.clone();
+        let rules_tx = ui.controller_tx.clone();
         ui.cursive.add_fullscreen_layer(
             OnEventView::new(
                 LinearLayout::vertical()
                     .child(
                         LinearLayout::horizontal()
                             .child(
-                                Panel::new(feeds_select.with_name("feeds").scrollable())
-                                    .title("Feeds")
+                                LinearLayout::vertical()
+                                    .child(
+                                        Panel::new(feeds_select.with_name("feeds").scrollable())
+                                            .title("Feeds")
+                                            .full_height(),
+                                    )
+                                    .child(queue_view(queue_select, ui.controller_tx.clone()))
                                     .full_width(),
                             )
                             .child(
@@ -88,10 +131,40 @@ impl Ui {
             .on_event('a', move |s| {
                 let controller_tx_clone = controller_tx_clone.clone();
                 add_feed_dialog(s, controller_tx_clone);
+            })
+            .on_event('I', move |s| {
+                opml_path_dialog(s, "Import OPML", "", import_tx.clone(), |path| {
+                    ControllerMessage::ImportOpml(path)
+                });
+            })
+            .on_event('E', move |s| {
+                opml_path_dialog(
+                    s,
+                    "Export OPML",
+                    "~/minicast.opml",
+                    export_tx.clone(),
+                    ControllerMessage::ExportOpml,
+                );
+            })
+            .on_event('i', move |s| {
+                let feed = s.find_name::<SelectView<u32>>("feeds").unwrap();
+                if let Some(id) = feed.selection() {
+                    interval_tx
+                        .send(ControllerMessage::EditRefreshInterval(*id))
+                        .unwrap();
+                }
+            })
+            .on_event('o', move |s| {
+                let feed = s.find_name::<SelectView<u32>>("feeds").unwrap();
+                if let Some(id) = feed.selection() {
+                    rules_tx
+                        .send(ControllerMessage::EditFeedRules(*id))
+                        .unwrap();
+                }
             }),
         );
 
-        ui
+        Ok(ui)
     }
 
     fn add_global_callbacks(&mut self) {
@@ -112,27 +185,87 @@ impl Ui {
         let controller_tx_clone = self.controller_tx.clone();
         self.cursive.add_global_callback('r', move |s| {
             let feed = s.find_name::<SelectView<u32>>("feeds").unwrap();
-            match feed.selection() {
-                Some(id) => {
-                    controller_tx_clone
-                        .send(ControllerMessage::ReloadFeedEpisodes(*id))
-                        .unwrap();
-                }
-                _ => (),
-            };
+            if let Some(id) = feed.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::ReloadFeedEpisodes(*id))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('R', move |_| {
+            controller_tx_clone
+                .send(ControllerMessage::RefreshAllFeeds)
+                .unwrap();
         });
 
         let controller_tx_clone = self.controller_tx.clone();
         self.cursive.add_global_callback('d', move |s| {
             let feed = s.find_name::<SelectView<u32>>("feeds").unwrap();
-            match feed.selection() {
-                Some(id) => {
-                    controller_tx_clone
-                        .send(ControllerMessage::DeleteFeed(*id))
-                        .unwrap();
-                }
-                _ => (),
-            };
+            if let Some(id) = feed.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::DeleteFeed(*id))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('m', move |s| {
+            let episodes = s.find_name::<SelectView<u32>>("episodes").unwrap();
+            if let Some(id) = episodes.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::MarkPlayed(*id, true))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('u', move |s| {
+            let episodes = s.find_name::<SelectView<u32>>("episodes").unwrap();
+            if let Some(id) = episodes.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::MarkPlayed(*id, false))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('e', move |s| {
+            let episodes = s.find_name::<SelectView<u32>>("episodes").unwrap();
+            if let Some(id) = episodes.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::Enqueue(*id))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('D', move |s| {
+            let episodes = s.find_name::<SelectView<u32>>("episodes").unwrap();
+            if let Some(id) = episodes.selection() {
+                controller_tx_clone
+                    .send(ControllerMessage::Download(*id))
+                    .unwrap();
+            }
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('S', move |_| {
+            controller_tx_clone.send(ControllerMessage::Sync).unwrap();
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('P', move |_| {
+            controller_tx_clone
+                .send(ControllerMessage::RetentionReport)
+                .unwrap();
+        });
+
+        let controller_tx_clone = self.controller_tx.clone();
+        self.cursive.add_global_callback('n', move |_| {
+            controller_tx_clone
+                .send(ControllerMessage::PlayNext)
+                .unwrap();
         });
 
         self.cursive.add_global_callback('q', |s| s.quit());
@@ -151,15 +284,35 @@ impl Ui {
                 UiMessage::UpdateFeeds(feeds) => {
                     self.cursive
                         .call_on_name("feeds", |v: &mut SelectView<u32>| {
+                            let selected = v.selection();
                             v.clear();
                             v.add_all(feeds);
+                            keep_selection(v, selected);
                         });
                 }
                 UiMessage::UpdateEpisodes(episodes) => {
                     self.cursive
                         .call_on_name("episodes", |v: &mut SelectView<u32>| {
+                            let selected = v.selection();
                             v.clear();
-                            v.add_all(episodes);
+                            // played episodes are dimmed.
+                            v.add_all(episodes.into_iter().map(|(label, id, played)| {
+                                if played {
+                                    (StyledString::styled(label, ColorStyle::tertiary()), id)
+                                } else {
+                                    (StyledString::plain(label), id)
+                                }
+                            }));
+                            keep_selection(v, selected);
+                        });
+                }
+                UiMessage::UpdateQueue(queue) => {
+                    self.cursive
+                        .call_on_name("queue", |v: &mut SelectView<u32>| {
+                            let selected = v.selection();
+                            v.clear();
+                            v.add_all(queue);
+                            keep_selection(v, selected);
                         });
                 }
                 UiMessage::UpdatePlaying(t, d) => {
@@ -169,6 +322,36 @@ impl Ui {
                     self.cursive
                         .call_on_name("ep_description", |v: &mut TextView| v.set_content(d));
                 }
+                UiMessage::UpdatePlayingExtras(extras) => {
+                    let extras = if extras.is_empty() {
+                        extras
+                    } else {
+                        format!("\n{}", extras)
+                    };
+                    self.cursive
+                        .call_on_name("ep_extras", |v: &mut TextView| v.set_content(extras));
+                }
+                UiMessage::UpdateFeedDetails(details) => {
+                    self.cursive
+                        .call_on_name("feed_details", |v: &mut TextView| v.set_content(details));
+                }
+                UiMessage::ShowError(message) => {
+                    self.cursive.add_layer(Dialog::info(message).title("Error"));
+                }
+                UiMessage::ShowInfo(title, message) => {
+                    self.cursive.add_layer(Dialog::info(message).title(title));
+                }
+                UiMessage::ShowImport(summary) => {
+                    import_preview_dialog(&mut self.cursive, summary, self.controller_tx.clone());
+                }
+                UiMessage::ShowRefreshInterval(feed, interval) => {
+                    let c = self.controller_tx.clone();
+                    refresh_interval_dialog(&mut self.cursive, feed, interval, c);
+                }
+                UiMessage::ShowFeedRules(feed, rules) => {
+                    let c = self.controller_tx.clone();
+                    feed_rules_dialog(&mut self.cursive, feed, &rules, c);
+                }
                 UiMessage::UpdateProgress(value, format) => {
                     let mut output = self.cursive.find_name::<ProgressBar>("progress").unwrap();
                     output.set_value(value);
@@ -184,6 +367,19 @@ impl Ui {
     }
 }
 
+/// Select the item with the id that was selected before the view was
+/// refilled, if it is still there.
+fn keep_selection(view: &mut SelectView<u32>, selected: Option<std::rc::Rc<u32>>) {
+    let selected = match selected {
+        Some(id) => *id,
+        None => return,
+    };
+    let idx = view.iter().position(|(_, id)| *id == selected);
+    if let Some(idx) = idx {
+        view.set_selection(idx);
+    }
+}
+
 fn add_feed_dialog(s: &mut cursive::Cursive, c: mpsc::Sender<ControllerMessage>) {
     s.add_layer(
         OnEventView::new(ResizedView::new(
@@ -194,7 +390,17 @@ fn add_feed_dialog(s: &mut cursive::Cursive, c: mpsc::Sender<ControllerMessage>)
                 .content(
                     ListView::new()
                         .child("URL", EditView::new().with_name("new_feed_url"))
-                        .child("Name", EditView::new().with_name("new_feed_name")),
+                        .child("Name", EditView::new().with_name("new_feed_name"))
+                        .child(
+                            "",
+                            TextView::new("Leave the name empty to use the feed title."),
+                        )
+                        .child("Username", EditView::new().with_name("new_feed_username"))
+                        .child(
+                            "Password",
+                            EditView::new().secret().with_name("new_feed_password"),
+                        )
+                        .child("", TextView::new("Only for private feeds.")),
                 )
                 .button("Cancel", |s| {
                     s.pop_layer();
@@ -218,24 +424,297 @@ fn save_new_feed(s: &mut cursive::Cursive, c: &mpsc::Sender<ControllerMessage>)
         .call_on_name("new_feed_url", |v: &mut EditView| v.get_content())?
         .to_string();
 
-    if name.is_empty() || url.is_empty() {
+    // the name is optional, the channel title is used instead.
+    if url.is_empty() {
         return None;
     }
 
     // do some validation on the url...
 
-    c.send(ControllerMessage::AddNewFeed(name, url)).unwrap();
+    let username = s
+        .call_on_name("new_feed_username", |v: &mut EditView| v.get_content())?
+        .to_string();
+
+    let password = s
+        .call_on_name("new_feed_password", |v: &mut EditView| v.get_content())?
+        .to_string();
+
+    let credentials = if username.is_empty() && password.is_empty() {
+        None
+    } else {
+        Some(Credentials { username, password })
+    };
+
+    c.send(ControllerMessage::AddNewFeed(name, url, credentials))
+        .unwrap();
     s.pop_layer();
     Some(())
 }
 
+/// Ask for the path of an OPML file, `message` makes the request to send.
+fn opml_path_dialog<F>(
+    s: &mut cursive::Cursive,
+    title: &str,
+    initial: &str,
+    c: mpsc::Sender<ControllerMessage>,
+    message: F,
+) where
+    F: Fn(PathBuf) -> ControllerMessage + 'static,
+{
+    s.add_layer(
+        OnEventView::new(ResizedView::new(
+            SizeConstraint::AtLeast(60),
+            SizeConstraint::Free,
+            Dialog::new()
+                .title(title)
+                .content(ListView::new().child(
+                    "File",
+                    EditView::new().content(initial).with_name("opml_path"),
+                ))
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                })
+                .button("Ok", move |s| {
+                    let path = s
+                        .call_on_name("opml_path", |v: &mut EditView| v.get_content())
+                        .unwrap();
+                    if path.trim().is_empty() {
+                        return;
+                    }
+                    c.send(message(config::expand_home(path.trim()))).unwrap();
+                    s.pop_layer();
+                }),
+        ))
+        .on_event(Key::Esc, |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// The feeds an OPML file adds, imported once confirmed.
+fn import_preview_dialog(
+    s: &mut cursive::Cursive,
+    summary: String,
+    c: mpsc::Sender<ControllerMessage>,
+) {
+    s.add_layer(
+        OnEventView::new(
+            Dialog::around(TextView::new(summary).scrollable())
+                .title("Import OPML")
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                })
+                .button("Import", move |s| {
+                    c.send(ControllerMessage::ConfirmImport).unwrap();
+                    s.pop_layer();
+                })
+                .max_height(30),
+        )
+        .on_event(Key::Esc, |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// The "up next" queue: `x` removes the selected episode, `K`/`J` move it
+/// up/down and `c` clears the queue.
+fn queue_view(
+    queue_select: SelectView<u32>,
+    c: mpsc::Sender<ControllerMessage>,
+) -> impl cursive::View {
+    let dequeue_tx = c.clone();
+    let up_tx = c.clone();
+    let down_tx = c.clone();
+
+    Panel::new(
+        OnEventView::new(queue_select.with_name("queue").scrollable())
+            .on_event('x', move |s| {
+                if let Some(id) = queue_selection(s) {
+                    dequeue_tx.send(ControllerMessage::Dequeue(id)).unwrap();
+                }
+            })
+            .on_event('K', move |s| {
+                if let Some(id) = queue_selection(s) {
+                    up_tx.send(ControllerMessage::MoveQueued(id, -1)).unwrap();
+                }
+            })
+            .on_event('J', move |s| {
+                if let Some(id) = queue_selection(s) {
+                    down_tx.send(ControllerMessage::MoveQueued(id, 1)).unwrap();
+                }
+            })
+            .on_event('c', move |_| {
+                c.send(ControllerMessage::ClearQueue).unwrap();
+            }),
+    )
+    .title("Up Next")
+    .full_height()
+}
+
+fn queue_selection(s: &mut cursive::Cursive) -> Option<u32> {
+    let queue = s.find_name::<SelectView<u32>>("queue")?;
+    let id = queue.selection()?;
+    Some(*id)
+}
+
+/// Ask for the refresh interval of a feed in minutes, empty goes back to
+/// the default and 0 turns automatic refreshes off. Starts from the
+/// current `interval`, in seconds.
+fn refresh_interval_dialog(
+    s: &mut cursive::Cursive,
+    feed: u32,
+    interval: Option<u32>,
+    c: mpsc::Sender<ControllerMessage>,
+) {
+    let minutes = interval
+        .map(|interval| (interval / 60).to_string())
+        .unwrap_or_default();
+    s.add_layer(
+        OnEventView::new(
+            Dialog::new()
+                .title("Refresh Interval")
+                .content(
+                    ListView::new()
+                        .child(
+                            "Minutes",
+                            EditView::new()
+                                .content(minutes)
+                                .with_name("refresh_interval"),
+                        )
+                        .child(
+                            "",
+                            TextView::new("Empty uses the default, 0 never refreshes."),
+                        ),
+                )
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                })
+                .button("Save", move |s| {
+                    let minutes = s
+                        .call_on_name("refresh_interval", |v: &mut EditView| v.get_content())
+                        .unwrap();
+                    let interval = match minutes.trim() {
+                        "" => None,
+                        minutes => match minutes.parse::<u32>().ok() {
+                            Some(minutes) => Some(minutes.saturating_mul(60)),
+                            None => return,
+                        },
+                    };
+                    c.send(ControllerMessage::SetRefreshInterval(feed, interval))
+                        .unwrap();
+                    s.pop_layer();
+                }),
+        )
+        .on_event(Key::Esc, |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Ask for the download and retention rules of a feed, starting from its
+/// current `rules`. Empty fields turn a rule off.
+fn feed_rules_dialog(
+    s: &mut cursive::Cursive,
+    feed: u32,
+    rules: &FeedRules,
+    c: mpsc::Sender<ControllerMessage>,
+) {
+    let auto_download = match rules.auto_download {
+        0 => String::new(),
+        count => count.to_string(),
+    };
+    let keep_episodes = rules
+        .keep_episodes
+        .map(|count| count.to_string())
+        .unwrap_or_default();
+    let keep_megabytes = rules
+        .keep_bytes
+        .map(|bytes| (bytes / 1_000_000).to_string())
+        .unwrap_or_default();
+    s.add_layer(
+        OnEventView::new(
+            Dialog::new()
+                .title("Downloads")
+                .content(
+                    ListView::new()
+                        .child(
+                            "Download newest",
+                            EditView::new()
+                                .content(auto_download)
+                                .with_name("auto_download"),
+                        )
+                        .child(
+                            "Delete played",
+                            Checkbox::new()
+                                .with_checked(rules.delete_played)
+                                .with_name("delete_played"),
+                        )
+                        .child(
+                            "Keep episodes",
+                            EditView::new()
+                                .content(keep_episodes)
+                                .with_name("keep_episodes"),
+                        )
+                        .child(
+                            "Keep MB",
+                            EditView::new()
+                                .content(keep_megabytes)
+                                .with_name("keep_megabytes"),
+                        )
+                        .child(
+                            "",
+                            TextView::new("Empty fields turn a rule off, the newest are kept."),
+                        ),
+                )
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                })
+                .button("Save", move |s| {
+                    if let Some(rules) = read_feed_rules(s) {
+                        c.send(ControllerMessage::SetFeedRules(feed, rules))
+                            .unwrap();
+                        s.pop_layer();
+                    }
+                }),
+        )
+        .on_event(Key::Esc, |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// The rules in the dialog, `None` when a number doesn't parse.
+fn read_feed_rules(s: &mut cursive::Cursive) -> Option<FeedRules> {
+    fn number(s: &mut cursive::Cursive, name: &str) -> Option<Option<u64>> {
+        let content = s.call_on_name(name, |v: &mut EditView| v.get_content())?;
+        match content.trim() {
+            "" => Some(None),
+            number => number.parse().ok().map(Some),
+        }
+    }
+
+    let auto_download = number(s, "auto_download")?.unwrap_or(0);
+    let keep_episodes = number(s, "keep_episodes")?;
+    let keep_megabytes = number(s, "keep_megabytes")?;
+    let delete_played = s.call_on_name("delete_played", |v: &mut Checkbox| v.is_checked())?;
+
+    Some(FeedRules {
+        auto_download: auto_download.min(u32::MAX as u64) as u32,
+        delete_played,
+        keep_episodes: keep_episodes.map(|count| count.min(u32::MAX as u64) as u32),
+        keep_bytes: keep_megabytes.map(|megabytes| megabytes.saturating_mul(1_000_000)),
+    })
+}
+
 fn details_view() -> impl cursive::View {
     Panel::new(
         LinearLayout::vertical()
             .child(TextView::new("Name:"))
             .child(TextView::new("").with_name("ep_title"))
             .child(TextView::new("\nDescription:"))
-            .child(TextView::new("").with_name("ep_description")),
+            .child(TextView::new("").with_name("ep_description"))
+            .child(TextView::new("").with_name("ep_extras"))
+            .child(TextView::new("").with_name("feed_details")),
     )
     .title("Details")
     .full_height()